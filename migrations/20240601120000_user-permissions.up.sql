@@ -0,0 +1,12 @@
+CREATE TABLE user_permissions (
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+
+    permission TEXT NOT NULL,
+    granted BOOLEAN NOT NULL,
+
+    PRIMARY KEY (person_id, permission)
+);
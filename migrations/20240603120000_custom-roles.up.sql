@@ -0,0 +1,20 @@
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    base_role user_role NOT NULL DEFAULT 'participant'
+);
+
+CREATE TABLE role_permissions (
+    role_id INT NOT NULL,
+    CONSTRAINT fk_role_id
+        FOREIGN KEY (role_id)
+        REFERENCES roles(id)
+        ON DELETE CASCADE,
+
+    permission TEXT NOT NULL,
+
+    PRIMARY KEY (role_id, permission)
+);
+
+ALTER TABLE people ADD COLUMN role_id INT;
+ALTER TABLE people ADD CONSTRAINT role_id_fk_constraint FOREIGN KEY (role_id) REFERENCES roles (id) ON DELETE SET NULL;
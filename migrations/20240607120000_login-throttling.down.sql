@@ -0,0 +1,2 @@
+ALTER TABLE people DROP COLUMN locked_until;
+DROP TABLE login_attempts;
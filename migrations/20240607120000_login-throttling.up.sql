@@ -0,0 +1,12 @@
+CREATE TABLE login_attempts (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    attempted_at TIMESTAMP NOT NULL DEFAULT now(),
+    succeeded BOOLEAN NOT NULL
+);
+
+CREATE INDEX login_attempts_username_idx ON login_attempts (LOWER(username), attempted_at);
+CREATE INDEX login_attempts_ip_idx ON login_attempts (ip, attempted_at);
+
+ALTER TABLE people ADD COLUMN locked_until TIMESTAMP;
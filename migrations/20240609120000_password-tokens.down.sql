@@ -0,0 +1,2 @@
+ALTER TABLE people ADD COLUMN password_link_id INT;
+DROP TABLE password_tokens;
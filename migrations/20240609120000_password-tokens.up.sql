@@ -0,0 +1,10 @@
+CREATE TABLE password_tokens (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL REFERENCES people(id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP
+);
+
+ALTER TABLE people DROP COLUMN password_link_id;
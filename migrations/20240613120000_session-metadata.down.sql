@@ -0,0 +1,8 @@
+DROP INDEX sessions_person_id;
+
+ALTER TABLE sessions
+    DROP COLUMN public_id,
+    DROP COLUMN person_id,
+    DROP COLUMN ip,
+    DROP COLUMN created_at,
+    DROP COLUMN last_seen;
@@ -0,0 +1,8 @@
+ALTER TABLE sessions
+    ADD COLUMN public_id TEXT,
+    ADD COLUMN person_id INT REFERENCES people(id) ON DELETE CASCADE,
+    ADD COLUMN ip TEXT,
+    ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now(),
+    ADD COLUMN last_seen TIMESTAMP NOT NULL DEFAULT now();
+
+CREATE INDEX sessions_person_id ON sessions(person_id);
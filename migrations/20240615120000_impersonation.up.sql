@@ -0,0 +1,8 @@
+CREATE TABLE impersonation_audit (
+    id SERIAL PRIMARY KEY,
+    impersonator_id INT REFERENCES people(id) ON DELETE SET NULL,
+    target_id INT REFERENCES people(id) ON DELETE SET NULL,
+    started BOOLEAN NOT NULL,
+    ip TEXT,
+    happened_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,13 @@
+CREATE TABLE security_events (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    username TEXT NOT NULL,
+    person_id INT REFERENCES people(id) ON DELETE SET NULL,
+    ip TEXT,
+    user_agent TEXT,
+    details TEXT,
+    happened_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX security_events_happened_at ON security_events(happened_at);
+CREATE INDEX security_events_username ON security_events(LOWER(username));
@@ -0,0 +1,16 @@
+CREATE TABLE email_outbox (
+    id SERIAL PRIMARY KEY,
+    person_id INT REFERENCES people(id) ON DELETE SET NULL,
+    kind TEXT NOT NULL,
+    to_username TEXT NOT NULL,
+    -- the whole email, cleared once it has been sent as it can contain one-time tokens
+    payload JSONB,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'failed')),
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+    sent_at TIMESTAMP
+);
+
+CREATE INDEX email_outbox_due ON email_outbox (status, next_attempt_at);
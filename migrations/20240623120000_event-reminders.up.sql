@@ -0,0 +1,6 @@
+CREATE TABLE event_reminders_sent (
+    event_id INT NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+    person_id INT NOT NULL REFERENCES people(id) ON DELETE CASCADE,
+    sent_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (event_id, person_id)
+);
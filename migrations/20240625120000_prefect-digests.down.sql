@@ -0,0 +1 @@
+DROP TABLE prefect_digest_runs;
@@ -0,0 +1,5 @@
+-- one row per weekly digest, so that each one only goes out once
+CREATE TABLE prefect_digest_runs (
+    scheduled_for TIMESTAMP PRIMARY KEY,
+    sent_at TIMESTAMP NOT NULL DEFAULT now()
+);
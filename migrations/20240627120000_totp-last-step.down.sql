@@ -0,0 +1 @@
+ALTER TABLE people DROP COLUMN totp_last_step;
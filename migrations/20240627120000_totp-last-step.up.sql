@@ -0,0 +1,2 @@
+-- the time step of the last TOTP code that worked, so that the same code can't be used twice
+ALTER TABLE people ADD COLUMN totp_last_step BIGINT;
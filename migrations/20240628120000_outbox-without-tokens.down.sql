@@ -0,0 +1 @@
+-- nothing to put back, as emails without tokens still get sent the same way
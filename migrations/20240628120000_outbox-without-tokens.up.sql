@@ -0,0 +1,2 @@
+-- links now get made when their email is sent, so any tokens still waiting in the outbox aren't needed
+UPDATE email_outbox SET payload = payload #- '{kind,LoginLink,token}' #- '{kind,SetPassword,token}' WHERE payload IS NOT NULL;
@@ -1,12 +1,28 @@
 #![allow(clippy::match_same_arms)]
 
 pub mod add_password;
+pub mod api_tokens;
 pub mod backend;
-pub mod cloudflare_turnstile;
+pub mod captcha;
+pub mod client_ip;
+pub mod csrf;
+pub mod impersonation;
+pub mod ldap;
 pub mod login;
+pub mod magic_link;
+pub mod oidc;
+pub mod password;
 pub mod pg_session;
+pub mod security_events;
+pub mod sessions;
+pub mod throttle;
+pub mod tokens;
+pub mod two_factor;
 
-use crate::{auth::backend::Auth, error::VentError};
+use crate::{
+    auth::{backend::Auth, impersonation::get_impersonator},
+    error::VentError,
+};
 use axum_login::AuthzBackend;
 use heck::AsSnakeCase;
 use itertools::Itertools;
@@ -32,7 +48,17 @@ impl PermissionsRole {
     }
 }
 
-#[derive(strum::EnumIter, strum::IntoStaticStr, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(
+    strum::EnumIter,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    Hash,
+)]
 pub enum PermissionsTarget {
     DevAccess,
     ImportCSV,
@@ -48,7 +74,8 @@ pub enum PermissionsTarget {
     AddRmSelfToEvent,
     SeePhotos,
     AddPhotos,
-    SeePeople
+    SeePeople,
+    EditRoles,
 }
 
 impl PermissionsTarget {
@@ -68,15 +95,32 @@ impl PermissionsTarget {
             PermissionsTarget::AddRmSelfToEvent => role >= PermissionsRole::Participant,
             PermissionsTarget::SeePhotos => role >= PermissionsRole::Participant,
             PermissionsTarget::AddPhotos => role >= PermissionsRole::Prefect,
-            PermissionsTarget::SeePeople => role >= PermissionsRole::Prefect
+            PermissionsTarget::SeePeople => role >= PermissionsRole::Prefect,
+            PermissionsTarget::EditRoles => role >= PermissionsRole::Admin,
         }
     }
+
+    ///Parses a permission stored in the database, logging and ignoring ones that no longer exist
+    pub fn from_db(name: &str) -> Option<Self> {
+        match name.parse() {
+            Ok(perm) => Some(perm),
+            Err(e) => {
+                warn!(?e, ?name, "Unknown permission in DB");
+                None
+            }
+        }
+    }
+
+    ///The name used for this permission in liquid templates, eg. `add_photos`
+    pub fn snake_case_name(self) -> String {
+        let pre_snake_case: &'static str = self.into();
+        AsSnakeCase(pre_snake_case).to_string()
+    }
 }
 
 pub async fn get_auth_object(auth: Auth) -> Result<Object, VentError> {
     let iter = PermissionsTarget::iter().map(|x| {
-        let pre_snake_case: &'static str = x.into();
-        (x, AsSnakeCase(pre_snake_case).to_string().parse().expect("unable to convert string to kstring"))
+        (x, x.snake_case_name().parse().expect("unable to convert string to kstring"))
     });
 
     match &auth.user {
@@ -92,7 +136,9 @@ pub async fn get_auth_object(auth: Auth) -> Result<Object, VentError> {
                 perms.insert(snake, Value::Scalar(allowed.contains(&variant).into()));
             }
 
-            Ok(liquid::object!({"is_logged_in": true, "permissions": perms, "user": x}))
+            let impersonator = get_impersonator();
+
+            Ok(liquid::object!({"is_logged_in": true, "permissions": perms, "user": x, "impersonator": impersonator}))
         }
         None => {
             let mut perms = Object::new();
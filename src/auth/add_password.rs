@@ -1,25 +1,32 @@
 use crate::{
     auth::{
         backend::Auth,
-        cloudflare_turnstile::{verify_turnstile, GrabCFRemoteIP},
+        captcha::verify_captcha,
+        client_ip::GrabRemoteIP,
         get_auth_object,
+        login::finish_login,
+        password::{check_password_policy, hash_password},
+        security_events::{record_security_event, RequestDetails, SecurityEventKind},
+        tokens::{
+            check_token, consume_token, send_link_in_background, Link, TokenPurpose, TokenStatus,
+        },
     },
     error::{SqlxAction, SqlxSnafu, VentError},
     liquid_utils::compile,
-    state::{db_objects::DbPerson, mail::EmailToSend, VentState},
+    state::{
+        db_objects::{AuthorisationBackendPerson, DbPerson},
+        VentState,
+    },
 };
 use axum::{
     extract::{Path, Query, State},
-    response::{IntoResponse, Redirect},
-    routing::get,
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
     Form, Router,
 };
-use bcrypt::{hash, DEFAULT_COST};
-use itertools::Itertools;
-use rand::{thread_rng, Rng};
 use serde::Deserialize;
 use snafu::ResultExt;
-use sqlx::{pool::PoolConnection, Postgres};
+use tower_sessions::Session;
 
 //tried to use an Option<Path<_>>, but didn't work
 #[axum::debug_handler]
@@ -39,45 +46,13 @@ async fn get_blank_add_password(
     .await
 }
 
-#[derive(Debug, Deserialize)]
-struct Link {
-    code: i32,
-}
-
-#[axum::debug_handler]
-async fn get_add_password(
+async fn render_add_password(
     auth: Auth,
-    State(state): State<VentState>,
-    Path(id): Path<i32>,
-    Query(Link { code: link_thingie }): Query<Link>,
-) -> Result<impl IntoResponse, VentError> {
-    let correct_code = sqlx::query!("SELECT password_link_id FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .password_link_id;
-
-    let Some(correct_code) = correct_code else {
-        return Ok(Redirect::to("/login_failure/no_numbers").into_response());
-    };
-    if correct_code != link_thingie {
-        return Ok(Redirect::to("/login_failure/failed_numbers").into_response());
-    };
-
-    if sqlx::query!("SELECT hashed_password FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .hashed_password
-        .is_some()
-    {
-        return Ok(Redirect::to("/login_failure/password_already_set").into_response());
-    }
-
+    state: &VentState,
+    id: i32,
+    code: String,
+    password_errors: Vec<String>,
+) -> Result<Html<String>, VentError> {
     let person = sqlx::query_as!(
         DbPerson,
         r#"
@@ -91,74 +66,111 @@ WHERE id = $1"#,
 
     let aa = get_auth_object(auth).await?;
 
-    Ok(compile(
+    compile(
         "www/add_password.liquid",
         liquid::object!({
             "is_authing_user": true,
             "person": person,
             "auth": aa,
-            "link_id": link_thingie
+            "code": code,
+            "password_errors": password_errors,
         }),
         &state.settings.brand.instance_name,
     )
-    .await?
-    .into_response())
+    .await
+}
+
+#[axum::debug_handler]
+async fn get_add_password(
+    auth: Auth,
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+    Query(Link { code }): Query<Link>,
+) -> Result<impl IntoResponse, VentError> {
+    if let Some(url) = check_token(&state, TokenPurpose::SetPassword, id, &code)
+        .await?
+        .failure_url()
+    {
+        return Ok(Redirect::to(url).into_response());
+    }
+
+    Ok(render_add_password(auth, &state, id, code, vec![])
+        .await?
+        .into_response())
 }
 
 #[derive(Deserialize)]
 struct AddPasswordForm {
     pub id: i32,
     pub unhashed_password: String,
-    pub password_link_id: i32,
-    #[serde(rename = "cf-turnstile-response")]
-    pub cf_turnstile_response: String,
+    pub code: String,
+    #[serde(
+        rename = "cf-turnstile-response",
+        alias = "h-captcha-response",
+        default
+    )]
+    pub captcha_response: String,
 }
 
 #[axum::debug_handler]
 async fn post_add_password(
     mut auth: Auth,
+    session: Session,
     State(state): State<VentState>,
-    remote_ip: GrabCFRemoteIP,
+    remote_ip: GrabRemoteIP,
+    request: RequestDetails,
     Form(AddPasswordForm {
         id,
         unhashed_password,
-        password_link_id,
-        cf_turnstile_response,
+        code,
+        captcha_response,
     }): Form<AddPasswordForm>,
 ) -> Result<impl IntoResponse, VentError> {
-    if !verify_turnstile(cf_turnstile_response, remote_ip).await? {
-        return Ok(Redirect::to("/login_failure/failed_turnstile"));
+    if !verify_captcha(&state, &captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha").into_response());
     }
 
-    if sqlx::query!("SELECT hashed_password FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .hashed_password
-        .is_some()
-    {
-        return Ok(Redirect::to("/login_failure/password_already_set"));
+    let token_id = match check_token(&state, TokenPurpose::SetPassword, id, &code).await? {
+        TokenStatus::Valid { token_id } => token_id,
+        status => {
+            return Ok(Redirect::to(
+                status
+                    .failure_url()
+                    .expect("only valid tokens don't have a failure url"),
+            )
+            .into_response())
+        }
+    };
+
+    let details = sqlx::query!(
+        "SELECT username, first_name, surname FROM people WHERE id = $1",
+        id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(id.into()),
+    })?;
+    let password_errors = check_password_policy(
+        &state.settings.auth.password_policy,
+        &unhashed_password,
+        &[&details.username, &details.first_name, &details.surname],
+    );
+    if !password_errors.is_empty() {
+        debug!(%id, "New password didn't meet the policy");
+        return Ok(render_add_password(auth, &state, id, code, password_errors)
+            .await?
+            .into_response());
     }
 
-    let expected = sqlx::query!("SELECT password_link_id FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .password_link_id;
-    let Some(expected) = expected else {
-        return Ok(Redirect::to("/login_failure/no_numbers"));
-    };
-    if expected != password_link_id {
-        return Ok(Redirect::to("/login_failure/failed_numbers"));
-    };
+    //check again whilst using it, in case the same link was submitted twice at once
+    if !consume_token(&state, TokenPurpose::SetPassword, id, token_id).await? {
+        return Ok(Redirect::to("/login_failure/used_link").into_response());
+    }
 
     //from here, we assume we're all good
 
-    let hashed = hash(&unhashed_password, DEFAULT_COST)?;
+    let hashed = hash_password(&state.settings.auth.password_hashing, &unhashed_password).await?;
     let person: DbPerson = sqlx::query_as!(
         DbPerson,
         r#"
@@ -173,68 +185,86 @@ RETURNING id, first_name, surname, username, form, hashed_password, permissions
     .fetch_one(&mut *state.get_connection().await?)
     .await.context(SqlxSnafu { action: SqlxAction::UpdatingPerson(id.into()) })?;
 
-    auth.login(&(person.into())).await?;
+    record_security_event(
+        &state,
+        SecurityEventKind::PasswordChanged,
+        &person.username,
+        Some("from emailed link".into()),
+        &request,
+    )
+    .await?;
 
-    Ok(Redirect::to("/"))
+    //still needs their second factor, as having someone's emails shouldn't be enough to get past it
+    let user: AuthorisationBackendPerson = person.into();
+    Ok(Redirect::to(
+        finish_login(
+            &mut auth,
+            &session,
+            &state,
+            &user,
+            "password reset",
+            &request,
+            None,
+        )
+        .await?,
+    )
+    .into_response())
 }
 
-pub async fn get_email_to_be_sent_for_reset_password(
-    mut connection: PoolConnection<Postgres>,
-    user_id: i32,
-) -> Result<EmailToSend, VentError> {
-    let current_ids =
-        sqlx::query!(r#"SELECT password_link_id FROM people WHERE password_link_id <> NULL"#)
-            .fetch_all(&mut *connection)
-            .await
-            .context(SqlxSnafu {
-                action: SqlxAction::FindingPerson(user_id.into()),
-            })?
-            .into_iter()
-            .map(|x| x.password_link_id.unwrap()) //we check for null above so fine
-            .collect_vec();
-
-    let id: i32 = {
-        let mut rng = thread_rng();
-        let mut tester = rng.gen::<u16>();
-        while current_ids.contains(&(tester.into())) {
-            tester = rng.gen::<u16>();
-        }
-        tester
-    }
-    .into(); //ensure always positive
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    pub username: String,
+    #[serde(
+        rename = "cf-turnstile-response",
+        alias = "h-captcha-response",
+        default
+    )]
+    pub captcha_response: String,
+}
 
-    sqlx::query!(
-        "UPDATE people SET password_link_id = $1, hashed_password = NULL WHERE id = $2",
-        id,
-        user_id
-    )
-    .execute(&mut *connection)
-    .await
-    .context(SqlxSnafu {
-        action: SqlxAction::UpdatingPerson(id.into()),
-    })?;
+#[axum::debug_handler]
+async fn post_forgot_password(
+    State(state): State<VentState>,
+    remote_ip: GrabRemoteIP,
+    request: RequestDetails,
+    Form(ForgotPasswordForm {
+        username,
+        captcha_response,
+    }): Form<ForgotPasswordForm>,
+) -> Result<impl IntoResponse, VentError> {
+    if !verify_captcha(&state, &captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha"));
+    }
 
-    let person = sqlx::query!(
-        "SELECT username, first_name, surname FROM people WHERE id = $1",
-        user_id
-    )
-    .fetch_one(&mut *connection)
-    .await
-    .context(SqlxSnafu {
-        action: SqlxAction::FindingPerson(id.into()),
-    })?;
+    send_link_in_background(
+        state,
+        username.clone(),
+        "forgotten password",
+        move |state, person| async move {
+            info!(%person.id, "Sending forgotten password email");
+            if let Err(e) = state.reset_password(person.id).await {
+                error!(?e, "Error sending forgotten password email");
+            } else if let Err(e) = record_security_event(
+                &state,
+                SecurityEventKind::PasswordResetSent,
+                &username,
+                Some("forgotten password".into()),
+                &request,
+            )
+            .await
+            {
+                error!(?e, "Error recording forgotten password email");
+            }
+        },
+    );
 
-    Ok(EmailToSend {
-        to_username: person.username,
-        to_id: user_id,
-        to_fullname: format!("{} {}", person.first_name, person.surname),
-        unique_id: id,
-    })
+    Ok(Redirect::to("/add_password"))
 }
 
 pub fn router() -> Router<VentState> {
     Router::new()
         .route("/add_password", get(get_blank_add_password))
+        .route("/forgot_password", post(post_forgot_password))
         .route(
             "/add_password/:user_id",
             get(get_add_password).post(post_add_password),
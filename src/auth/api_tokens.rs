@@ -0,0 +1,316 @@
+//! Module that deals with personal API tokens - named, revocable bearer tokens which let scripts act as a person, limited to a subset of their permissions.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object,
+        tokens::{generate_token, hash_token},
+        PermissionsRole, PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::{compile_with_newtitle, CustomFormat},
+    routes::edit_self::render_edit_user,
+    state::{
+        db_objects::{AuthorisationBackendPerson, DbPerson},
+        VentState,
+    },
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Router,
+};
+use axum_extra::extract::Form;
+use axum_login::{login_required, AuthzBackend};
+use http::{header::AUTHORIZATION, Method};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use strum::IntoEnumIterator;
+use tower_sessions::Session;
+
+#[derive(Serialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub expired: bool,
+}
+
+///Gets all of someone's API tokens, for `/edit_user`
+pub async fn get_api_tokens(state: &VentState, person_id: i32) -> Result<Vec<ApiToken>, VentError> {
+    let format = &state.settings.niche.date_time_format;
+
+    Ok(sqlx::query!(
+        r#"
+SELECT id, name, permissions, created_at, expires_at, last_used_at, COALESCE(expires_at < now(), false) as "expired!"
+FROM api_tokens
+WHERE person_id = $1
+ORDER BY created_at DESC
+        "#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingApiTokens(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| ApiToken {
+        id: rec.id,
+        name: rec.name,
+        permissions: rec
+            .permissions
+            .iter()
+            .filter_map(|x| PermissionsTarget::from_db(x))
+            .map(PermissionsTarget::snake_case_name)
+            .collect(),
+        created_at: rec.created_at.to_env_string(format),
+        expires_at: rec.expires_at.map(|x| x.to_env_string(format)),
+        last_used_at: rec.last_used_at.map(|x| x.to_env_string(format)),
+        expired: rec.expired,
+    })
+    .collect())
+}
+
+///The only routes which API tokens work for - everything else needs a proper login, so that eg. a leaked export token can't change its owner's password or make itself a broader token
+const API_TOKEN_ROUTES: &[&str] = &["/csv_people", "/spreadsheet", "/ical"];
+
+///Gets the API token from an `Authorization: Bearer ...` header, if there is one
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+///Whether a request is for one of the export routes which API tokens can be used for
+pub fn is_api_token_route(request: &Request) -> bool {
+    request.method() == Method::GET && API_TOKEN_ROUTES.contains(&request.uri().path())
+}
+
+///Middleware which authenticates requests with an `Authorization: Bearer ...` header as the owner of that token
+///
+/// Needs to sit inside the auth layer, as it replaces the user in the [`Auth`] that the auth layer puts in the request
+pub async fn api_token_middleware(
+    State(state): State<VentState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    let Some(token) = bearer_token(&request).map(str::to_string) else {
+        return Ok(next.run(request).await);
+    };
+
+    if !is_api_token_route(&request) {
+        warn!(method = ?request.method(), uri = ?request.uri(), "API token used for a route that doesn't allow them");
+        return Err(VentError::ApiTokenNotAllowed);
+    }
+
+    let mut conn = state.get_connection().await?;
+
+    let Some(rec) = sqlx::query!(
+        r#"
+SELECT t.id as token_id, t.permissions as token_permissions, p.id, p.first_name, p.surname, p.username, p.form, p.hashed_password, p.permissions as "permissions: PermissionsRole", p.was_first_entry
+FROM api_tokens t
+INNER JOIN people p ON p.id = t.person_id
+WHERE t.token_hash = $1 AND (t.expires_at IS NULL OR t.expires_at > now())
+        "#,
+        hash_token(&token)
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AuthenticatingApiToken,
+    })?
+    else {
+        warn!("Request with an invalid API token");
+        return Err(VentError::InvalidApiToken);
+    };
+
+    sqlx::query!(
+        "UPDATE api_tokens SET last_used_at = now() WHERE id = $1",
+        rec.token_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingApiTokens(rec.id.into()),
+    })?;
+    drop(conn);
+
+    debug!(person_id = %rec.id, token_id = %rec.token_id, "Authenticated with API token");
+
+    let mut user: AuthorisationBackendPerson = DbPerson {
+        id: rec.id,
+        first_name: rec.first_name,
+        surname: rec.surname,
+        username: rec.username,
+        was_first_entry: rec.was_first_entry,
+        form: rec.form,
+        hashed_password: rec.hashed_password,
+        permissions: rec.permissions,
+    }
+    .into();
+    user.token_scope = Some(
+        rec.token_permissions
+            .iter()
+            .filter_map(|x| PermissionsTarget::from_db(x))
+            .collect(),
+    );
+
+    if let Some(auth) = request.extensions_mut().get_mut::<Auth>() {
+        auth.user = Some(user);
+    } else {
+        error!("API token middleware is outside of the auth layer");
+    }
+
+    Ok(next.run(request).await)
+}
+
+///The longest anyone can ask for a token to last - anything longer should just never expire
+const MAX_API_TOKEN_DAYS: i32 = 3650;
+
+#[derive(Deserialize)]
+struct NewApiToken {
+    name: String,
+    ///Blank for a token that never expires
+    #[serde(default)]
+    expires_in_days: String,
+    #[serde(default)]
+    permissions: Vec<String>,
+}
+
+#[axum::debug_handler]
+async fn post_create_api_token(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(NewApiToken {
+        name,
+        expires_in_days,
+        permissions,
+    }): Form<NewApiToken>,
+) -> Result<impl IntoResponse, VentError> {
+    let user = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to make a token");
+    let person_id = user.id;
+
+    //tokens can't do anything that their owner can't
+    let allowed = auth.backend.get_all_permissions(user).await?;
+    let permissions: Vec<&'static str> = permissions
+        .iter()
+        .filter_map(|x| PermissionsTarget::from_db(x))
+        .filter(|x| allowed.contains(x))
+        .map(Into::into)
+        .collect();
+
+    let expires_in_days = match expires_in_days.trim() {
+        "" => None,
+        x => match x.parse::<i32>() {
+            Ok(days) if (1..=MAX_API_TOKEN_DAYS).contains(&days) => Some(days),
+            _ => {
+                debug!(%person_id, ?expires_in_days, "Invalid API token expiry");
+                let error = format!(
+                    "API tokens have to expire between 1 and {MAX_API_TOKEN_DAYS} days from now - leave it blank for a token that never expires."
+                );
+                return Ok(
+                    render_edit_user(auth, &session, &state, vec![], vec![error])
+                        .await?
+                        .into_response(),
+                );
+            }
+        },
+    };
+
+    info!(%person_id, ?name, ?permissions, "Creating API token");
+
+    let (token, token_hash) = generate_token();
+
+    sqlx::query!(
+        r#"
+INSERT INTO api_tokens (person_id, name, token_hash, permissions, expires_at)
+VALUES ($1, $2, $3, $4, now() + make_interval(days => $5))
+        "#,
+        person_id,
+        name,
+        token_hash,
+        &permissions as &[&str],
+        expires_in_days
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingApiTokens(person_id.into()),
+    })?;
+
+    let aa = get_auth_object(auth).await?;
+
+    compile_with_newtitle(
+        "www/api_token_created.liquid",
+        liquid::object!({ "auth": aa, "name": name, "token": token, "domain": state.settings.brand.domain.clone() }),
+        &state.settings.brand.instance_name,
+        Some("New API Token".into()),
+    )
+    .await
+    .map(IntoResponse::into_response)
+}
+
+#[derive(Deserialize)]
+struct RevokeApiToken {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_revoke_api_token(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(RevokeApiToken { id }): Form<RevokeApiToken>,
+) -> Result<impl IntoResponse, VentError> {
+    let person_id = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to revoke a token")
+        .id;
+
+    info!(%person_id, %id, "Revoking API token");
+
+    sqlx::query!(
+        "DELETE FROM api_tokens WHERE id = $1 AND person_id = $2",
+        id,
+        person_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingApiTokens(person_id.into()),
+    })?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+///The permissions someone could give a new token, for the form on `/edit_user`
+pub async fn get_grantable_permissions(auth: &Auth) -> Result<Vec<String>, VentError> {
+    let Some(user) = &auth.user else {
+        return Ok(vec![]);
+    };
+    let allowed = auth.backend.get_all_permissions(user).await?;
+
+    Ok(PermissionsTarget::iter()
+        .filter(|x| allowed.contains(x))
+        .map(|x| <&'static str>::from(x).to_string())
+        .collect())
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/api_tokens/create", post(post_create_api_token))
+        .route("/api_tokens/revoke", post(post_revoke_api_token))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
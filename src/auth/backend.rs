@@ -1,5 +1,10 @@
 use crate::{
-    auth::{login::LoginCreds, PermissionsTarget},
+    auth::{
+        ldap::{ldap_authenticate, LdapOutcome},
+        login::LoginCreds,
+        password::{hash_password, needs_rehash, verify_password},
+        PermissionsRole, PermissionsTarget,
+    },
     error::{LoginFailureReason, SqlxAction, SqlxSnafu, VentError},
     state::{
         db_objects::{AuthorisationBackendPerson, DbPerson},
@@ -7,7 +12,6 @@ use crate::{
     },
 };
 use axum_login::{AuthSession, AuthnBackend, AuthzBackend, UserId};
-use bcrypt::verify;
 use snafu::ResultExt;
 use std::collections::HashSet;
 
@@ -22,6 +26,81 @@ impl VentAuthBackend {
     pub fn new(state: VentState) -> Self {
         Self { state }
     }
+
+    ///Gets the individual permission overrides for a person - `true` means the permission has been granted, `false` means it has been denied
+    pub async fn get_permission_overrides(
+        &self,
+        person_id: i32,
+    ) -> Result<Vec<(PermissionsTarget, bool)>, VentError> {
+        Ok(sqlx::query!(
+            "SELECT permission, granted FROM user_permissions WHERE person_id = $1",
+            person_id
+        )
+        .fetch_all(&mut *self.state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingPermissionOverrides(person_id.into()),
+        })?
+        .into_iter()
+        .filter_map(|rec| {
+            PermissionsTarget::from_db(&rec.permission).map(|perm| (perm, rec.granted))
+        })
+        .collect())
+    }
+
+    pub async fn get_person_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<DbPerson>, VentError> {
+        sqlx::query_as!(
+            DbPerson,
+            r#"
+SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+FROM people
+WHERE LOWER(username) = LOWER($1)
+        "#,
+            username
+        )
+        .fetch_optional(&mut *self.state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingPerson(username.to_string().into()),
+        })
+    }
+
+    ///Gets the permissions that come from a person's role - either from their custom role if they have one, or from the built-in `role`
+    pub async fn get_role_permissions(
+        &self,
+        person_id: i32,
+        role: PermissionsRole,
+    ) -> Result<HashSet<PermissionsTarget>, VentError> {
+        let mut conn = self.state.get_connection().await?;
+
+        let role_id = sqlx::query!("SELECT role_id FROM people WHERE id = $1", person_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingPerson(person_id.into()),
+            })?
+            .and_then(|rec| rec.role_id);
+
+        let Some(role_id) = role_id else {
+            return Ok(role.can());
+        };
+
+        Ok(sqlx::query!(
+            "SELECT permission FROM role_permissions WHERE role_id = $1",
+            role_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingRole(role_id),
+        })?
+        .into_iter()
+        .filter_map(|rec| PermissionsTarget::from_db(&rec.permission))
+        .collect())
+    }
 }
 
 #[async_trait::async_trait]
@@ -37,19 +116,29 @@ impl AuthnBackend for VentAuthBackend {
             unhashed_password,
         }: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let db_user = sqlx::query_as!(
-            DbPerson,
-            r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
-FROM people
-WHERE LOWER(username) = LOWER($1)
-        "#,
-            username
-            )
-            .fetch_optional(&mut *self.state.get_connection().await?)
-            .await.context(SqlxSnafu {action: SqlxAction::FindingPerson(username.into())})?;
+        if let Some(ldap_settings) = &self.state.settings.ldap {
+            match ldap_authenticate(ldap_settings, &username, &unhashed_password).await {
+                Ok(LdapOutcome::Authenticated(directory_username)) => {
+                    return Ok(self
+                        .get_person_by_username(&directory_username)
+                        .await?
+                        .map(Into::into));
+                }
+                Ok(LdapOutcome::WrongPassword) => {
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::IncorrectPassword,
+                    });
+                }
+                Ok(LdapOutcome::NotInDirectory) => {}
+                Err(e) => {
+                    //falling back here would let anyone who can knock over the directory log in with an old local password
+                    error!(?e, "Unable to check LDAP");
+                    return Err(e);
+                }
+            }
+        }
 
-        let Some(db_user) = db_user else {
+        let Some(db_user) = self.get_person_by_username(&username).await? else {
             return Ok(None);
         };
         let Some(hashed_password) = &db_user.hashed_password else {
@@ -59,7 +148,32 @@ WHERE LOWER(username) = LOWER($1)
             });
         };
 
-        if verify(unhashed_password, hashed_password)? {
+        if verify_password(&unhashed_password, hashed_password).await? {
+            let hashing_settings = &self.state.settings.auth.password_hashing;
+            if needs_rehash(hashing_settings, hashed_password) {
+                info!(id = %db_user.id, "Upgrading password hash");
+
+                let new_hash = hash_password(hashing_settings, &unhashed_password).await?;
+                sqlx::query!(
+                    "UPDATE people SET hashed_password = $1 WHERE id = $2",
+                    new_hash,
+                    db_user.id
+                )
+                .execute(&mut *self.state.get_connection().await?)
+                .await
+                .context(SqlxSnafu {
+                    action: SqlxAction::UpdatingPerson(db_user.id.into()),
+                })?;
+
+                return Ok(Some(
+                    DbPerson {
+                        hashed_password: Some(new_hash),
+                        ..db_user
+                    }
+                    .into(),
+                ));
+            }
+
             Ok(Some(db_user.into()))
         } else {
             Err(VentError::LoginFailure {
@@ -90,16 +204,42 @@ impl AuthzBackend for VentAuthBackend {
 
     async fn get_user_permissions(
         &self,
-        _user: &Self::User,
+        user: &Self::User,
     ) -> Result<HashSet<Self::Permission>, Self::Error> {
-        //TODO: individual permissions for things like photos?
-        Ok(HashSet::new())
+        Ok(self
+            .get_permission_overrides(user.id)
+            .await?
+            .into_iter()
+            .filter_map(|(perm, granted)| granted.then_some(perm))
+            .collect())
     }
 
     async fn get_group_permissions(
         &self,
         user: &Self::User,
     ) -> Result<HashSet<Self::Permission>, Self::Error> {
-        Ok(user.permissions.can())
+        self.get_role_permissions(user.id, user.permissions).await
+    }
+
+    ///Overriden so that individual denials can take away permissions granted by the role, and so that API tokens can only use what they were given
+    async fn get_all_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        let mut all = self.get_group_permissions(user).await?;
+
+        for (perm, granted) in self.get_permission_overrides(user.id).await? {
+            if granted {
+                all.insert(perm);
+            } else {
+                all.remove(&perm);
+            }
+        }
+
+        if let Some(scope) = &user.token_scope {
+            all.retain(|perm| scope.contains(perm));
+        }
+
+        Ok(all)
     }
 }
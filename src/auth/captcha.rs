@@ -0,0 +1,234 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use liquid::Object;
+use reqwest::Client;
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::{
+    collections::HashMap,
+    env::var,
+    fmt::{Debug, Display, Formatter},
+    sync::Arc,
+};
+
+use crate::{
+    auth::client_ip::GrabRemoteIP,
+    cfg::{CaptchaProvider, CaptchaSettings},
+    error::{ReqwestAction, ReqwestSnafu, SerdeJsonAction, VentError},
+    state::VentState,
+};
+
+tokio::task_local! {
+    ///The [`CaptchaVerifier::template_info`] from the state, whilst a request is being handled - used by [`captcha_info`] to put the widget on forms
+    static CAPTCHA_INFO: Object;
+}
+
+///Something that can check the response from a captcha widget
+#[async_trait]
+pub trait CaptchaVerifier: Debug + Send + Sync {
+    ///returns whether or not it worked
+    async fn verify(&self, response: &str, remote_ip: &str) -> Result<bool, VentError>;
+
+    ///The information templates need to show the widget - `kind`, `sitekey` and `script_url`
+    fn template_info(&self) -> Object;
+}
+
+///Used when captchas are turned off in the settings - everything passes
+#[derive(Debug)]
+pub struct DisabledCaptcha;
+
+#[async_trait]
+impl CaptchaVerifier for DisabledCaptcha {
+    async fn verify(&self, _response: &str, _remote_ip: &str) -> Result<bool, VentError> {
+        Ok(true)
+    }
+
+    fn template_info(&self) -> Object {
+        liquid::object!({
+            "kind": "disabled",
+            "sitekey": "",
+            "script_url": "",
+        })
+    }
+}
+
+///Cloudflare Turnstile and hCaptcha both use the same `siteverify` style of API, so they share an implementation
+#[derive(Debug)]
+pub struct SiteverifyCaptcha {
+    kind: &'static str,
+    sitekey: String,
+    secret: String,
+    verify_url: String,
+    script_url: &'static str,
+    client: Client,
+}
+
+impl SiteverifyCaptcha {
+    pub const TURNSTILE_VERIFY_URL: &'static str =
+        "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+    pub const HCAPTCHA_VERIFY_URL: &'static str = "https://api.hcaptcha.com/siteverify";
+}
+
+#[derive(Deserialize, Debug)]
+struct SiteverifyResponse {
+    pub success: bool,
+    pub challenge_ts: Option<String>,
+    pub hostname: Option<String>,
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+    pub action: Option<String>,
+    pub cdata: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum CommonHeaders {
+    CaptchaSecret,
+    CaptchaResponse,
+    RemoteIP,
+    ContentType,
+}
+
+impl Display for CommonHeaders {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommonHeaders::CaptchaSecret => write!(f, "secret"),
+            CommonHeaders::CaptchaResponse => write!(f, "response"),
+            CommonHeaders::RemoteIP => write!(f, "remoteip"),
+            CommonHeaders::ContentType => Display::fmt(&http::header::CONTENT_TYPE, f),
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for SiteverifyCaptcha {
+    async fn verify(&self, response: &str, remote_ip: &str) -> Result<bool, VentError> {
+        let mut body = HashMap::new();
+        body.insert(
+            CommonHeaders::CaptchaSecret.to_string(),
+            self.secret.as_str(),
+        );
+        body.insert(CommonHeaders::CaptchaResponse.to_string(), response);
+        body.insert(CommonHeaders::RemoteIP.to_string(), remote_ip);
+
+        debug!(?remote_ip, kind = ?self.kind, "Checking for captcha response");
+
+        let post_response = self
+            .client
+            .post(&self.verify_url)
+            .form(&body)
+            .send()
+            .await
+            .context(ReqwestSnafu {
+                action: ReqwestAction::VerifyingCaptcha,
+            })?
+            .error_for_status()
+            .with_context(|e| ReqwestSnafu {
+                action: ReqwestAction::RErrorForStatus(e.status()),
+            })?
+            .json::<SiteverifyResponse>()
+            .await
+            .context(ReqwestSnafu {
+                action: ReqwestAction::ConvertToJson(SerdeJsonAction::CaptchaResponse),
+            })?;
+
+        debug!(?post_response.hostname, ?post_response.cdata, ?post_response.action, ?post_response.challenge_ts, "Got captcha response");
+
+        if post_response.success {
+            return Ok(true);
+        }
+
+        if !post_response.error_codes.is_empty() {
+            error!(?post_response.error_codes, kind = ?self.kind, "Captcha Response Error");
+        }
+
+        Ok(false)
+    }
+
+    fn template_info(&self) -> Object {
+        liquid::object!({
+            "kind": self.kind,
+            "sitekey": self.sitekey.as_str(),
+            "script_url": self.script_url,
+        })
+    }
+}
+
+///Builds the captcha verifier chosen in the settings
+///
+/// For backwards compatibility, Turnstile keys can also come from the `CFT_SITEKEY` and `CFT_SECRETKEY` environment variables
+pub fn build_captcha(settings: &CaptchaSettings) -> Arc<dyn CaptchaVerifier> {
+    let (kind, default_verify_url, script_url, sitekey_env, secret_env) = match settings.provider {
+        CaptchaProvider::Disabled => {
+            warn!("Captchas are disabled");
+            return Arc::new(DisabledCaptcha);
+        }
+        CaptchaProvider::Turnstile => (
+            "turnstile",
+            SiteverifyCaptcha::TURNSTILE_VERIFY_URL,
+            "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            Some("CFT_SITEKEY"),
+            Some("CFT_SECRETKEY"),
+        ),
+        CaptchaProvider::HCaptcha => (
+            "hcaptcha",
+            SiteverifyCaptcha::HCAPTCHA_VERIFY_URL,
+            "https://js.hcaptcha.com/1/api.js",
+            None,
+            None,
+        ),
+    };
+
+    let from_settings_or_env = |setting: &Option<String>, env: Option<&str>, name: &str| {
+        setting
+            .clone()
+            .or_else(|| env.and_then(|env| var(env).ok()))
+            .unwrap_or_else(|| panic!("missing `captcha.{name}` for {kind}"))
+    };
+
+    Arc::new(SiteverifyCaptcha {
+        kind,
+        sitekey: from_settings_or_env(&settings.sitekey, sitekey_env, "sitekey"),
+        secret: from_settings_or_env(&settings.secret, secret_env, "secret"),
+        verify_url: settings
+            .verify_url
+            .clone()
+            .unwrap_or_else(|| default_verify_url.to_string()),
+        script_url,
+        client: Client::new(),
+    })
+}
+
+///returns whether or not it worked
+///
+/// Forms should take the response with `#[serde(rename = "cf-turnstile-response", alias = "h-captcha-response", default)]` so that every provider works
+pub async fn verify_captcha(
+    state: &VentState,
+    response: &str,
+    remote_ip: &GrabRemoteIP,
+) -> Result<bool, VentError> {
+    state.captcha.verify(response, remote_ip.as_str()).await
+}
+
+///Gets the details of the captcha widget for the page being rendered
+pub fn captcha_info() -> Object {
+    CAPTCHA_INFO.try_with(Clone::clone).unwrap_or_else(|_e| {
+        error!(
+            "Rendering a page outside of the captcha middleware - forms on it won't show a captcha"
+        );
+        Object::default()
+    })
+}
+
+///Middleware which makes [`CAPTCHA_INFO`] available to any templates rendered whilst handling the request
+pub async fn captcha_info_middleware(
+    State(state): State<VentState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    CAPTCHA_INFO
+        .scope(state.captcha.template_info(), next.run(request))
+        .await
+}
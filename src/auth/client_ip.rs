@@ -0,0 +1,200 @@
+//! Module that works out which IP a request came from - forwarding headers (`X-Forwarded-For`, `Forwarded` and `CF-Connecting-IP`) are only believed when they were added by a trusted proxy.
+
+use crate::{error::VentError, state::VentState};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+fn is_trusted(trusted_proxies: &[IpNet], ip: IpAddr) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+///Parses one node from a forwarding header, which could be eg. `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:4711"` or `unknown`
+///
+/// IPv4-mapped IPv6 addresses (eg. `::ffff:1.2.3.4`) come back as plain IPv4, so that they match the trusted proxies and the throttling the same way as the socket address does
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    let ip: IpAddr = if let Some(rest) = node.strip_prefix('[') {
+        rest.split_once(']')?.0.parse().ok()?
+    } else if let Ok(ip) = node.parse() {
+        ip
+    } else {
+        node.rsplit_once(':')?.0.parse().ok()?
+    };
+    Some(ip.to_canonical())
+}
+
+///Gets every address from `X-Forwarded-For`, in the order they were added
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(parse_node)
+        .collect()
+}
+
+///Gets every `for=` address from `Forwarded`, in the order they were added
+fn forwarded(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("Forwarded")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    parse_node(value)
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+///Works backwards from the socket - each address is only believed if everything after it is a trusted proxy
+pub fn client_ip(headers: &HeaderMap, socket: IpAddr, trusted_proxies: &[IpNet]) -> IpAddr {
+    if !is_trusted(trusted_proxies, socket) {
+        return socket;
+    }
+
+    let mut hops = x_forwarded_for(headers);
+    if hops.is_empty() {
+        hops = forwarded(headers);
+    }
+
+    if hops.is_empty() {
+        return headers
+            .get("CF-Connecting-IP")
+            .and_then(|x| x.to_str().ok())
+            .and_then(parse_node)
+            .unwrap_or(socket);
+    }
+
+    //if every hop is a trusted proxy, then the first one is the best we've got
+    hops.iter()
+        .rev()
+        .find(|ip| !is_trusted(trusted_proxies, **ip))
+        .or_else(|| hops.first())
+        .copied()
+        .unwrap_or(socket)
+}
+
+pub struct GrabRemoteIP(String);
+
+impl GrabRemoteIP {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<VentState> for GrabRemoteIP {
+    type Rejection = VentError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &VentState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(socket)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            error!("Failed to get socket address - is the server passing it through?");
+            return Err(VentError::MissingRemoteIP);
+        };
+
+        Ok(Self(
+            client_ip(
+                &parts.headers,
+                socket.ip().to_canonical(),
+                &state.settings.proxy.trusted_proxies,
+            )
+            .to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_socket_ignores_headers() {
+        let headers = headers(&[
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("CF-Connecting-IP", "5.6.7.8"),
+        ]);
+        assert_eq!(
+            client_ip(&headers, ip("203.0.113.5"), &proxies()),
+            ip("203.0.113.5")
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_x_forwarded_for_is_ignored() {
+        //the client sent `6.6.6.6` itself, and our proxy added the address it actually saw
+        let headers = headers(&[("X-Forwarded-For", "6.6.6.6, 198.51.100.7")]);
+        assert_eq!(
+            client_ip(&headers, ip("10.0.0.1"), &proxies()),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped() {
+        let headers = headers(&[("X-Forwarded-For", "198.51.100.7, 10.1.2.3")]);
+        assert_eq!(
+            client_ip(&headers, ip("10.0.0.1"), &proxies()),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_with_quoted_v6_and_port() {
+        let headers = headers(&[("Forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)]);
+        assert_eq!(
+            client_ip(&headers, ip("10.0.0.1"), &proxies()),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn cf_connecting_ip_fallback() {
+        let headers = headers(&[("CF-Connecting-IP", "198.51.100.9")]);
+        assert_eq!(
+            client_ip(&headers, ip("10.0.0.1"), &proxies()),
+            ip("198.51.100.9")
+        );
+    }
+
+    #[test]
+    fn mapped_v4_hops_are_canonicalised() {
+        let headers = headers(&[("X-Forwarded-For", "198.51.100.7, ::ffff:10.1.2.3")]);
+        assert_eq!(
+            client_ip(&headers, ip("10.0.0.1"), &proxies()),
+            ip("198.51.100.7")
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Module that protects sessions against cross-site request forgery - each session which has been shown a form gets a random token, which every state-changing request has to send back in either a `csrf_token` form field or query parameter, or an `X-CSRF-Token` header.
+
+use crate::{
+    auth::{
+        api_tokens::{bearer_token, is_api_token_route},
+        tokens::{generate_token, secrets_match},
+    },
+    error::{ReadingBodySnafu, TowerSessionsSnafu, VentError},
+};
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{FromRequest, Query, Request},
+    http::{header::CONTENT_TYPE, request::Parts},
+    middleware::Next,
+    response::Response,
+    Form,
+};
+use serde::Deserialize;
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+tokio::task_local! {
+    ///The current session, whilst a request is being handled - used by [`form_token`] to find or make the token for any forms being rendered
+    static CSRF_SESSION: Session;
+}
+
+///The session key for the token
+const SESSION_CSRF_KEY: &str = "vent.csrf";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+///Should match the `DefaultBodyLimit` in `main`
+const MAX_BODY_BYTES: usize = 1024 * 1024 * 50;
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+///Makes a copy of a request with an already-read body, so the copy can be given to extractors
+fn copy_request(parts: &Parts, bytes: &Bytes) -> Request {
+    let mut request = Request::new(Body::from(bytes.clone()));
+    *request.method_mut() = parts.method.clone();
+    *request.headers_mut() = parts.headers.clone();
+    request
+}
+
+///Finds the token that a request sent, giving back the request to carry on with
+///
+/// Only urlencoded form bodies get read into memory to do this - file uploads send it as a query parameter instead, so that they don't need buffering
+async fn find_sent_token(request: Request) -> Result<(Request, Option<String>), VentError> {
+    if let Some(sent) = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|x| x.to_str().ok())
+    {
+        let sent = sent.to_string();
+        return Ok((request, Some(sent)));
+    }
+
+    if let Ok(Query(CsrfForm { csrf_token })) = Query::<CsrfForm>::try_from_uri(request.uri()) {
+        if !csrf_token.is_empty() {
+            return Ok((request, Some(csrf_token)));
+        }
+    }
+
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((request, None));
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .context(ReadingBodySnafu)?;
+
+    let sent = Form::<CsrfForm>::from_request(copy_request(&parts, &bytes), &())
+        .await
+        .ok()
+        .map(|Form(CsrfForm { csrf_token })| csrf_token);
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), sent))
+}
+
+///Gets the current session's token for putting into forms, making one if the session doesn't have one yet
+///
+/// Only happens when a page gets rendered, so that requests which never show a form (eg. `/healthcheck` or calendar feeds) don't get a session made just to hold a token
+pub async fn form_token() -> Result<String, VentError> {
+    let Ok(session) = CSRF_SESSION.try_with(Clone::clone) else {
+        error!("Rendering a page outside of the CSRF middleware - forms on it won't work");
+        return Ok(String::new());
+    };
+
+    if let Some(token) = session
+        .get::<String>(SESSION_CSRF_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    {
+        return Ok(token);
+    }
+
+    let (token, _) = generate_token();
+    session
+        .insert(SESSION_CSRF_KEY, &token)
+        .await
+        .context(TowerSessionsSnafu)?;
+    Ok(token)
+}
+
+///Middleware which rejects unsafe requests (eg. `POST`s) which don't send back the token from [`form_token`]
+///
+/// Anonymous sessions get one too when they're shown a form, so that the login forms can't be submitted from other sites to log someone into an account of the attacker's choosing
+///
+/// Needs to sit inside the auth layer, but outside the API token middleware - requests with API tokens for the export routes don't need checking, as browsers never send those by themselves
+pub async fn csrf_middleware(
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    if bearer_token(&request).is_some() && is_api_token_route(&request) {
+        return Ok(next.run(request).await);
+    }
+
+    let request = if request.method().is_safe() {
+        request
+    } else {
+        //if the session has no token, then it was never shown a form that this could have come from
+        let token: Option<String> = session
+            .get(SESSION_CSRF_KEY)
+            .await
+            .context(TowerSessionsSnafu)?;
+        let (request, sent) = find_sent_token(request).await?;
+
+        if !token
+            .zip(sent)
+            .is_some_and(|(token, sent)| secrets_match(&token, &sent))
+        {
+            warn!(method = ?request.method(), uri = ?request.uri(), "Request with a missing or wrong CSRF token");
+            return Err(VentError::CsrfMismatch);
+        }
+
+        request
+    };
+
+    Ok(CSRF_SESSION.scope(session, next.run(request)).await)
+}
@@ -0,0 +1,208 @@
+//! Module that lets developers temporarily view the site as someone else, to help debug what they can see - every start and stop gets written to `impersonation_audit`, and nothing can be changed until they stop.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        client_ip::GrabRemoteIP,
+        PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    state::VentState,
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Form, Router,
+};
+use axum_login::{login_required, permission_required, AuthnBackend};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+///The session key under which we store who is really logged in whilst impersonating someone
+const IMPERSONATOR_KEY: &str = "vent.impersonator";
+///The only things which can be posted whilst impersonating someone - everything else is read-only
+const ALLOWED_WHILST_IMPERSONATING: &[&str] = &["/impersonate/stop", "/logout"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Impersonator {
+    pub id: i32,
+    pub first_name: String,
+    pub surname: String,
+}
+
+tokio::task_local! {
+    ///Who is really logged in, whilst a request is being handled - set by [`impersonation_middleware`] for [`get_impersonator`]
+    static IMPERSONATOR: Option<Impersonator>;
+}
+
+///Gets who is really logged in, if the current user is being impersonated
+pub fn get_impersonator() -> Option<Impersonator> {
+    IMPERSONATOR.try_with(Clone::clone).unwrap_or_else(|_e| {
+        error!("Checking for impersonation outside of the impersonation middleware");
+        None
+    })
+}
+
+///Middleware which reads who is really logged in out of the session, for [`get_impersonator`], and stops anything being changed whilst impersonating
+///
+/// Needs to sit inside the auth layer
+pub async fn impersonation_middleware(
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    let impersonator: Option<Impersonator> = session
+        .get(IMPERSONATOR_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    if let Some(impersonator) = &impersonator {
+        //otherwise any changes would look like they were made by the person being impersonated
+        if !request.method().is_safe()
+            && !ALLOWED_WHILST_IMPERSONATING.contains(&request.uri().path())
+        {
+            warn!(impersonator = %impersonator.id, method = %request.method(), path = %request.uri().path(), "Tried to change something whilst impersonating");
+            return Err(VentError::WhilstImpersonating);
+        }
+    }
+
+    Ok(IMPERSONATOR.scope(impersonator, next.run(request)).await)
+}
+
+async fn record_impersonation(
+    state: &VentState,
+    impersonator_id: i32,
+    target_id: i32,
+    started: bool,
+    remote_ip: Option<&GrabRemoteIP>,
+) -> Result<(), VentError> {
+    sqlx::query!(
+        "INSERT INTO impersonation_audit (impersonator_id, target_id, started, ip) VALUES ($1, $2, $3, $4)",
+        impersonator_id,
+        target_id,
+        started,
+        remote_ip.map(GrabRemoteIP::as_str)
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingImpersonationAudit,
+    })?;
+
+    Ok(())
+}
+
+///Records that an impersonation ended because the impersonated session logged out
+pub async fn record_impersonation_logout(
+    auth: &Auth,
+    state: &VentState,
+    remote_ip: Option<&GrabRemoteIP>,
+) -> Result<(), VentError> {
+    if let (Some(impersonator), Some(user)) = (get_impersonator(), &auth.user) {
+        info!(impersonator = %impersonator.id, target = %user.id, "Impersonation ended by logging out");
+        record_impersonation(state, impersonator.id, user.id, false, remote_ip).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct StartImpersonation {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_start_impersonation(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: Option<GrabRemoteIP>,
+    Form(StartImpersonation { id }): Form<StartImpersonation>,
+) -> Result<impl IntoResponse, VentError> {
+    let current = auth
+        .user
+        .clone()
+        .expect("need to be logged in to impersonate");
+
+    if current.id == id || get_impersonator().is_some() {
+        warn!(impersonator = %current.id, target = %id, "Refusing to start impersonation");
+        return Ok(Redirect::to(&format!("/edit_person/{id}")));
+    }
+
+    let Some(target) = auth.backend.get_user(&id).await? else {
+        return Ok(Redirect::to("/show_people"));
+    };
+
+    info!(impersonator = %current.id, target = %id, "Starting impersonation");
+    record_impersonation(&state, current.id, id, true, remote_ip.as_ref()).await?;
+
+    auth.login(&target).await?;
+    session
+        .insert(
+            IMPERSONATOR_KEY,
+            Impersonator {
+                id: current.id,
+                first_name: current.first_name,
+                surname: current.surname,
+            },
+        )
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(Redirect::to("/"))
+}
+
+#[axum::debug_handler]
+async fn post_stop_impersonation(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: Option<GrabRemoteIP>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(impersonator) = get_impersonator() else {
+        return Ok(Redirect::to("/"));
+    };
+    let target_id = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to stop impersonating")
+        .id;
+
+    info!(impersonator = %impersonator.id, target = %target_id, "Stopping impersonation");
+    record_impersonation(
+        &state,
+        impersonator.id,
+        target_id,
+        false,
+        remote_ip.as_ref(),
+    )
+    .await?;
+
+    session
+        .remove::<Impersonator>(IMPERSONATOR_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    if let Some(original) = auth.backend.get_user(&impersonator.id).await? {
+        auth.login(&original).await?;
+        Ok(Redirect::to(&format!("/edit_person/{target_id}")))
+    } else {
+        auth.logout().await?;
+        Ok(Redirect::to("/"))
+    }
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/impersonate/start", post(post_start_impersonation))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+        .route("/impersonate/stop", post(post_stop_impersonation))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
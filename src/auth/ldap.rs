@@ -0,0 +1,87 @@
+//! Module that deals with checking passwords against an LDAP directory (eg. Active Directory or `OpenLDAP`), for deployments that keep their identities there.
+
+use crate::{cfg::LdapSettings, error::VentError};
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use std::time::Duration;
+
+///The result code for a bind with the wrong password
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Debug)]
+pub enum LdapOutcome {
+    ///Nobody in the directory has that username, so we should fall back to local passwords
+    NotInDirectory,
+    ///The bind worked - this is the username from the directory, to be matched against `people.username`
+    Authenticated(String),
+    WrongPassword,
+}
+
+///Finds someone in the directory, and then checks their password by binding as them
+pub async fn ldap_authenticate(
+    settings: &LdapSettings,
+    username: &str,
+    unhashed_password: &str,
+) -> Result<LdapOutcome, VentError> {
+    //most servers treat a bind with an empty password as an anonymous bind, which would always work
+    if unhashed_password.is_empty() {
+        return Ok(LdapOutcome::WrongPassword);
+    }
+
+    let conn_settings = LdapConnSettings::new()
+        .set_conn_timeout(Duration::from_secs(settings.timeout_secs))
+        .set_starttls(settings.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(conn_settings, &settings.url).await?;
+    ldap3::drive!(conn);
+
+    if let (Some(bind_dn), Some(bind_password)) = (&settings.bind_dn, &settings.bind_password) {
+        ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+    }
+
+    let filter = format!(
+        "(&{}({}={}))",
+        settings.filter,
+        settings.username_attribute,
+        ldap_escape(username)
+    );
+    let (entries, _res) = ldap
+        .search(
+            &settings.base_dn,
+            Scope::Subtree,
+            &filter,
+            vec![settings.username_attribute.as_str()],
+        )
+        .await?
+        .success()?;
+
+    let mut entries = entries.into_iter().map(SearchEntry::construct);
+    let Some(entry) = entries.next() else {
+        debug!(?username, "Not in LDAP directory");
+        ldap.unbind().await?;
+        return Ok(LdapOutcome::NotInDirectory);
+    };
+    if entries.next().is_some() {
+        warn!(
+            ?username,
+            "Multiple LDAP entries for one username - refusing to guess"
+        );
+        ldap.unbind().await?;
+        return Ok(LdapOutcome::WrongPassword);
+    }
+
+    let directory_username = entry
+        .attrs
+        .get(&settings.username_attribute)
+        .and_then(|values| values.first())
+        .cloned()
+        .unwrap_or_else(|| username.to_string());
+
+    let bind = ldap.simple_bind(&entry.dn, unhashed_password).await?;
+    ldap.unbind().await?;
+
+    if bind.rc == INVALID_CREDENTIALS {
+        return Ok(LdapOutcome::WrongPassword);
+    }
+    bind.success()?;
+
+    Ok(LdapOutcome::Authenticated(directory_username))
+}
@@ -1,29 +1,39 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        cloudflare_turnstile::{verify_turnstile, GrabCFRemoteIP},
+        captcha::verify_captcha,
+        client_ip::GrabRemoteIP,
         get_auth_object,
+        impersonation::record_impersonation_logout,
+        security_events::{record_security_event, RequestDetails, SecurityEventKind},
+        throttle::{forget_attempt, record_failure, record_success, start_attempt, LoginAttempt},
+        two_factor::{second_factor_needed, start_second_factor, SecondFactor},
     },
     error::{ALError, LoginFailureReason, VentError},
     liquid_utils::compile,
-    state::VentState,
+    state::{db_objects::AuthorisationBackendPerson, VentState},
 };
 use axum::{
     extract::{Path, State},
     response::{IntoResponse, Redirect},
-    routing::get,
+    routing::{get, post},
     Form, Router,
 };
 use axum_login::login_required;
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
 
 #[derive(Deserialize)]
 pub struct LoginForm {
     pub username: String,
     pub unhashed_password: String,
-    #[serde(rename = "cf-turnstile-response")]
-    pub cf_turnstile_response: String,
+    #[serde(
+        rename = "cf-turnstile-response",
+        alias = "h-captcha-response",
+        default
+    )]
+    pub captcha_response: String,
 }
 
 #[axum::debug_handler]
@@ -31,10 +41,21 @@ pub async fn get_login(
     auth: Auth,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
+    let sso_button_text = state.settings.oidc.as_ref().map(|oidc| {
+        oidc.button_text
+            .clone()
+            .unwrap_or_else(|| "Log in with single sign-on".into())
+    });
+
     let aa = get_auth_object(auth).await?;
     compile(
         "www/login.liquid",
-        liquid::object!({ "auth": aa, "tech_support_person": state.settings.tech_support_person.clone() }),
+        liquid::object!({
+            "auth": aa,
+            "tech_support_person": state.settings.tech_support_person.clone(),
+            "sso_button_text": sso_button_text,
+            "magic_link_enabled": !state.settings.auth.magic_link.enabled_for.is_empty(),
+        }),
         &state.settings.brand.instance_name,
     )
     .await
@@ -50,20 +71,34 @@ pub enum FailureReason {
     UserNotFound,
     #[serde(rename = "failed_numbers")]
     FailedNumbers,
-    #[serde(rename = "password_already_set")]
-    PasswordAlreadySet,
-    #[serde(rename = "failed_turnstile")]
-    FailedTurnstile,
+    #[serde(rename = "failed_captcha")]
+    FailedCaptcha,
+    #[serde(rename = "failed_2fa")]
+    Failed2FA,
+    #[serde(rename = "locked_out")]
+    LockedOut,
+    #[serde(rename = "expired_link")]
+    ExpiredLink,
+    #[serde(rename = "used_link")]
+    UsedLink,
+    #[serde(rename = "failed_sso")]
+    FailedSSO,
+    #[serde(rename = "slow_down")]
+    SlowDown,
 }
 
 impl FailureReason {
     pub fn status_code(self) -> StatusCode {
         match self {
-            Self::NoNumbers | Self::PasswordAlreadySet => StatusCode::BAD_REQUEST,
+            Self::NoNumbers => StatusCode::BAD_REQUEST,
             Self::UserNotFound => StatusCode::NOT_FOUND,
-            Self::FailedTurnstile | Self::FailedNumbers | Self::BadPassword => {
-                StatusCode::FORBIDDEN
-            }
+            Self::FailedCaptcha
+            | Self::FailedNumbers
+            | Self::BadPassword
+            | Self::Failed2FA
+            | Self::FailedSSO => StatusCode::FORBIDDEN,
+            Self::LockedOut | Self::SlowDown => StatusCode::TOO_MANY_REQUESTS,
+            Self::ExpiredLink | Self::UsedLink => StatusCode::GONE,
         }
     }
 }
@@ -91,20 +126,103 @@ pub struct LoginCreds {
     pub unhashed_password: String,
 }
 
+///For once someone has proved who they are (eg. with a password or single sign-on) - either logs them in, or sends them to the second factor. Returns where to redirect to.
+///
+/// `method` is how they proved it, for the security log, and `attempt_id` is the throttling attempt from [`start_attempt`] if there was one - it only gets marked as a success if no second factor is needed
+pub async fn finish_login(
+    auth: &mut Auth,
+    session: &Session,
+    state: &VentState,
+    user: &AuthorisationBackendPerson,
+    method: &str,
+    request: &RequestDetails,
+    attempt_id: Option<i32>,
+) -> Result<&'static str, VentError> {
+    Ok(match second_factor_needed(state, user).await? {
+        SecondFactor::NotNeeded => {
+            if let Some(attempt_id) = attempt_id {
+                record_success(state, &user.username, attempt_id).await?;
+            }
+            record_security_event(
+                state,
+                SecurityEventKind::LoginSucceeded,
+                &user.username,
+                Some(method.to_string()),
+                request,
+            )
+            .await?;
+            auth.login(user).await?;
+            "/"
+        }
+        second_factor => {
+            //`LoginSucceeded` only gets recorded once they pass the second factor, and until then the attempt still counts as a failure for throttling
+            record_security_event(
+                state,
+                SecurityEventKind::SecondFactorRequired,
+                &user.username,
+                Some(method.to_string()),
+                request,
+            )
+            .await?;
+            start_second_factor(session, user, second_factor).await?;
+            "/login_2fa"
+        }
+    })
+}
+
+///Records a failed login for throttling, and in the security log
+pub async fn login_failed(
+    state: &VentState,
+    username: &str,
+    reason: &str,
+    request: &RequestDetails,
+) -> Result<(), VentError> {
+    record_security_event(
+        state,
+        SecurityEventKind::LoginFailed,
+        username,
+        Some(reason.to_string()),
+        request,
+    )
+    .await?;
+
+    if record_failure(state, username).await? {
+        record_security_event(state, SecurityEventKind::LockedOut, username, None, request).await?;
+    }
+
+    Ok(())
+}
+
 #[axum::debug_handler]
 pub async fn post_login(
     mut auth: Auth,
-    remote_ip: GrabCFRemoteIP,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: GrabRemoteIP,
+    request: RequestDetails,
     Form(LoginForm {
         username,
         unhashed_password,
-        cf_turnstile_response,
+        captcha_response,
     }): Form<LoginForm>,
 ) -> Result<impl IntoResponse, VentError> {
-    if !verify_turnstile(cf_turnstile_response, remote_ip).await? {
-        return Ok(Redirect::to("/login_failure/failed_turnstile"));
+    if !verify_captcha(&state, &captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha"));
     }
 
+    let ip = remote_ip.as_str();
+    let attempt_id = match start_attempt(&state, &username, ip).await? {
+        LoginAttempt::Allowed { attempt_id } => attempt_id,
+        LoginAttempt::LockedOut => {
+            warn!(?username, ?ip, "Login attempt whilst locked out");
+            return Ok(Redirect::to("/login_failure/locked_out"));
+        }
+        LoginAttempt::TooSoon => {
+            warn!(?username, ?ip, "Login attempt too soon after failures");
+            return Ok(Redirect::to("/login_failure/slow_down"));
+        }
+    };
+
     Ok(Redirect::to(
         match auth
             .authenticate(LoginCreds {
@@ -114,16 +232,31 @@ pub async fn post_login(
             .await
         {
             Ok(Some(x)) => {
-                auth.login(&x).await?;
-                "/"
+                finish_login(
+                    &mut auth,
+                    &session,
+                    &state,
+                    &x,
+                    "password",
+                    &request,
+                    Some(attempt_id),
+                )
+                .await?
+            }
+            Ok(None) => {
+                login_failed(&state, &username, "unknown username", &request).await?;
+                "/login_failure/user_not_found"
             }
-            Ok(None) => "/login_failure/user_not_found",
             Err(error) => {
                 if let ALError::Backend(VentError::LoginFailure { reason }) = error {
                     match reason {
-                        LoginFailureReason::PasswordIsNotSet => "/add_password",
+                        LoginFailureReason::PasswordIsNotSet => {
+                            forget_attempt(&state, attempt_id).await?;
+                            "/add_password"
+                        }
                         LoginFailureReason::IncorrectPassword => {
                             error!(username = ? username, "Wrong password for trying to login");
+                            login_failed(&state, &username, "wrong password", &request).await?;
                             "/login_failure/bad_password"
                         }
                     }
@@ -136,14 +269,19 @@ pub async fn post_login(
 }
 
 #[axum::debug_handler]
-pub async fn get_logout(mut auth: Auth) -> Result<impl IntoResponse, VentError> {
+pub async fn post_logout(
+    mut auth: Auth,
+    State(state): State<VentState>,
+    remote_ip: Option<GrabRemoteIP>,
+) -> Result<impl IntoResponse, VentError> {
+    record_impersonation_logout(&auth, &state, remote_ip.as_ref()).await?;
     auth.logout().await?;
     Ok(Redirect::to("/"))
 }
 
 pub fn router() -> Router<VentState> {
     Router::new()
-        .route("/logout", get(get_logout))
+        .route("/logout", post(post_logout))
         .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
         .route("/login", get(get_login).post(post_login))
         .route(
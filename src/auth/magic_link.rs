@@ -0,0 +1,184 @@
+//! Module that lets people log in using a one-time link sent to their email, if their role is allowed to in `auth.magic_link`.
+
+use crate::{
+    auth::{
+        backend::Auth,
+        captcha::verify_captcha,
+        client_ip::GrabRemoteIP,
+        get_auth_object,
+        login::finish_login,
+        security_events::RequestDetails,
+        throttle::is_locked_out,
+        tokens::{
+            check_token, consume_token, send_link_in_background, Link, TokenPurpose, TokenStatus,
+        },
+        PermissionsRole,
+    },
+    error::VentError,
+    liquid_utils::compile,
+    state::VentState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Form, Router,
+};
+use axum_login::AuthnBackend;
+use serde::Deserialize;
+use tower_sessions::Session;
+
+///Whether people with this role are allowed to log in with an emailed link
+pub fn magic_link_allowed(state: &VentState, role: PermissionsRole) -> bool {
+    state.settings.auth.magic_link.enabled_for.contains(&role)
+}
+
+#[axum::debug_handler]
+async fn get_login_link_sent(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let aa = get_auth_object(auth).await?;
+    compile(
+        "www/login_link.liquid",
+        liquid::object!({
+            "is_confirming": false,
+            "auth": aa,
+        }),
+        &state.settings.brand.instance_name,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct LoginLinkRequestForm {
+    pub username: String,
+    #[serde(
+        rename = "cf-turnstile-response",
+        alias = "h-captcha-response",
+        default
+    )]
+    pub captcha_response: String,
+}
+
+#[axum::debug_handler]
+async fn post_request_login_link(
+    State(state): State<VentState>,
+    remote_ip: GrabRemoteIP,
+    Form(LoginLinkRequestForm {
+        username,
+        captcha_response,
+    }): Form<LoginLinkRequestForm>,
+) -> Result<impl IntoResponse, VentError> {
+    if !verify_captcha(&state, &captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha"));
+    }
+
+    //also doesn't give away whether they're allowed links
+    send_link_in_background(state, username, "login link", |state, person| async move {
+        if magic_link_allowed(&state, person.permissions) {
+            info!(%person.id, "Sending login link email");
+            if let Err(e) = state.send_login_link(person.id).await {
+                error!(?e, "Error sending login link email");
+            }
+        } else {
+            warn!(%person.id, ?person.permissions, "Login link asked for by someone whose role isn't allowed them");
+        }
+    });
+
+    Ok(Redirect::to("/login_link"))
+}
+
+///Doesn't log anyone in by itself - email scanners like to follow links, so the person has to press a button to use it
+#[axum::debug_handler]
+async fn get_login_link(
+    auth: Auth,
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+    Query(Link { code }): Query<Link>,
+) -> Result<impl IntoResponse, VentError> {
+    if let Some(url) = check_token(&state, TokenPurpose::Login, id, &code)
+        .await?
+        .failure_url()
+    {
+        return Ok(Redirect::to(url).into_response());
+    }
+
+    let aa = get_auth_object(auth).await?;
+    Ok(compile(
+        "www/login_link.liquid",
+        liquid::object!({
+            "is_confirming": true,
+            "auth": aa,
+            "id": id,
+            "code": code,
+        }),
+        &state.settings.brand.instance_name,
+    )
+    .await?
+    .into_response())
+}
+
+#[axum::debug_handler]
+async fn post_login_link(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: GrabRemoteIP,
+    request: RequestDetails,
+    Path(id): Path<i32>,
+    Form(Link { code }): Form<Link>,
+) -> Result<impl IntoResponse, VentError> {
+    let token_id = match check_token(&state, TokenPurpose::Login, id, &code).await? {
+        TokenStatus::Valid { token_id } => token_id,
+        status => {
+            return Ok(Redirect::to(
+                status
+                    .failure_url()
+                    .expect("only valid tokens don't have a failure url"),
+            ))
+        }
+    };
+
+    let Some(user) = auth.backend.get_user(&id).await? else {
+        return Ok(Redirect::to("/login_failure/user_not_found"));
+    };
+
+    //the settings or their role could have changed since the link was sent
+    if !magic_link_allowed(&state, user.permissions) {
+        warn!(%id, "Tried to use a login link without a role that's allowed them");
+        return Ok(Redirect::to("/login_failure/failed_numbers"));
+    }
+
+    let ip = remote_ip.as_str();
+    if is_locked_out(&state, &user.username, ip).await? {
+        warn!(%id, ?ip, "Login link used whilst locked out");
+        return Ok(Redirect::to("/login_failure/locked_out"));
+    }
+
+    if !consume_token(&state, TokenPurpose::Login, id, token_id).await? {
+        return Ok(Redirect::to("/login_failure/used_link"));
+    }
+
+    Ok(Redirect::to(
+        finish_login(
+            &mut auth,
+            &session,
+            &state,
+            &user,
+            "login link",
+            &request,
+            None,
+        )
+        .await?,
+    ))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route(
+            "/login_link",
+            get(get_login_link_sent).post(post_request_login_link),
+        )
+        .route("/login_link/:id", get(get_login_link).post(post_login_link))
+}
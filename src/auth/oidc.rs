@@ -0,0 +1,260 @@
+//! Module that deals with `OpenID` Connect single sign-on - the authorization code flow with PKCE, matching a claim from the identity provider to `people.username`.
+
+use crate::{
+    auth::{backend::Auth, login::finish_login, security_events::RequestDetails},
+    cfg::{OidcSettings, OidcUsernameClaim, Settings},
+    error::{OidcAction, SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    state::{db_objects::DbPerson, VentState},
+};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata},
+    reqwest::async_http_client,
+    AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
+    OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::fmt::Display;
+use tower_sessions::Session;
+
+///The session key under which we store what we need to check the identity provider's response
+const PENDING_OIDC_KEY: &str = "vent.pending_oidc";
+
+#[derive(Serialize, Deserialize)]
+struct PendingOidc {
+    csrf_state: String,
+    nonce: String,
+    pkce_verifier: String,
+}
+
+fn oidc_error(action: OidcAction, error: impl Display) -> VentError {
+    VentError::Oidc {
+        action,
+        error: error.to_string(),
+    }
+}
+
+///Discovers the provider's metadata and builds a client - done on each login, so that the site still starts if the provider is down
+async fn get_client(settings: &Settings, oidc: &OidcSettings) -> Result<CoreClient, VentError> {
+    let issuer_url = IssuerUrl::new(oidc.issuer_url.clone())
+        .map_err(|e| oidc_error(OidcAction::Discovering, e))?;
+    let metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
+        .await
+        .map_err(|e| oidc_error(OidcAction::Discovering, e))?;
+
+    let redirect_url = oidc
+        .redirect_url
+        .clone()
+        .unwrap_or_else(|| format!("{}/oidc/callback", settings.brand.domain));
+
+    Ok(CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(oidc.client_id.clone()),
+        oidc.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(
+        RedirectUrl::new(redirect_url).map_err(|e| oidc_error(OidcAction::Discovering, e))?,
+    ))
+}
+
+///Works out which username the identity provider is vouching for, if any
+fn username_from_claims(
+    settings: &Settings,
+    oidc: &OidcSettings,
+    claims: &CoreIdTokenClaims,
+) -> Option<String> {
+    match oidc.username_claim {
+        OidcUsernameClaim::Email => {
+            if claims.email_verified() == Some(false) {
+                warn!("Identity provider gave an unverified email");
+                return None;
+            }
+
+            //only accept emails from our domain, otherwise eg. `bob@gmail.com` could log in as `bob`
+            let email = claims.email()?.as_str();
+            let (username, domain) = email.rsplit_once('@')?;
+            if !domain.eq_ignore_ascii_case(&settings.mail.username_domain) {
+                warn!(
+                    ?email,
+                    "Identity provider gave an email from the wrong domain"
+                );
+                return None;
+            }
+
+            Some(username.to_string())
+        }
+        OidcUsernameClaim::PreferredUsername => {
+            claims.preferred_username().map(|x| x.as_str().to_string())
+        }
+        OidcUsernameClaim::Sub => Some(claims.subject().as_str().to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_oidc_login(
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(oidc) = &state.settings.oidc else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let client = get_client(&state.settings, oidc).await?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let scopes = if oidc.scopes.is_empty() {
+        vec!["email".to_string(), "profile".to_string()]
+    } else {
+        oidc.scopes.clone()
+    };
+
+    let (auth_url, csrf_state, nonce) = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scopes(scopes.into_iter().map(Scope::new))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    session
+        .insert(
+            PENDING_OIDC_KEY,
+            PendingOidc {
+                csrf_state: csrf_state.secret().clone(),
+                nonce: nonce.secret().clone(),
+                pkce_verifier: pkce_verifier.secret().clone(),
+            },
+        )
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    debug!("Redirecting to identity provider");
+
+    Ok(Redirect::to(auth_url.as_str()))
+}
+
+#[derive(Deserialize)]
+struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[axum::debug_handler]
+async fn get_oidc_callback(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    request: RequestDetails,
+    Query(OidcCallback {
+        code,
+        state: csrf_state,
+        error,
+    }): Query<OidcCallback>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(oidc) = &state.settings.oidc else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let pending: Option<PendingOidc> = session
+        .remove(PENDING_OIDC_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    if let Some(error) = error {
+        warn!(?error, "Identity provider returned an error");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    }
+
+    let (Some(pending), Some(code), Some(csrf_state)) = (pending, code, csrf_state) else {
+        warn!("Missing state for single sign-on callback");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    };
+
+    if pending.csrf_state != csrf_state {
+        warn!("Mismatched CSRF state for single sign-on callback");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    }
+
+    let client = get_client(&state.settings, oidc).await?;
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| oidc_error(OidcAction::ExchangingCode, e))?;
+
+    let id_token = token_response
+        .id_token()
+        .ok_or_else(|| oidc_error(OidcAction::VerifyingIdToken, "missing ID token"))?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &Nonce::new(pending.nonce))
+        .map_err(|e| oidc_error(OidcAction::VerifyingIdToken, e))?;
+
+    if let Some(expected_hash) = claims.access_token_hash() {
+        let actual_hash = AccessTokenHash::from_token(
+            token_response.access_token(),
+            &id_token
+                .signing_alg()
+                .map_err(|e| oidc_error(OidcAction::VerifyingIdToken, e))?,
+        )
+        .map_err(|e| oidc_error(OidcAction::VerifyingIdToken, e))?;
+
+        if actual_hash != *expected_hash {
+            warn!("Mismatched access token hash for single sign-on");
+            return Ok(Redirect::to("/login_failure/failed_sso"));
+        }
+    }
+
+    let Some(username) = username_from_claims(&state.settings, oidc, claims) else {
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    };
+
+    let Some(person) = sqlx::query_as!(
+        DbPerson,
+        r#"
+SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+FROM people
+WHERE LOWER(username) = LOWER($1)
+        "#,
+        username
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(username.clone().into()),
+    })?
+    else {
+        warn!(?username, "Single sign-on for unknown username");
+        return Ok(Redirect::to("/login_failure/user_not_found"));
+    };
+
+    info!(?username, "Logged in with single sign-on");
+
+    Ok(Redirect::to(
+        finish_login(
+            &mut auth,
+            &session,
+            &state,
+            &person.into(),
+            "single sign-on",
+            &request,
+            None,
+        )
+        .await?,
+    ))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/oidc/login", get(get_oidc_login))
+        .route("/oidc/callback", get(get_oidc_callback))
+}
@@ -0,0 +1,329 @@
+//! Module for hashing and checking passwords, and the policy new passwords have to follow. New hashes use whichever algorithm is in the settings (Argon2id by default), but older bcrypt hashes can still be checked and get upgraded when people next log in.
+
+use crate::{
+    cfg::{PasswordHashingSettings, PasswordPolicySettings},
+    error::{JoinSnafu, ThreadReason, VentError},
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::collections::HashSet;
+use tokio::task::spawn_blocking;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashingAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+}
+
+impl HashingAlgorithm {
+    ///Works out which algorithm made a given hash
+    pub fn of_hash(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2id$") {
+            Some(Self::Argon2id)
+        } else if hash.starts_with("$2") {
+            Some(Self::Bcrypt)
+        } else {
+            None
+        }
+    }
+}
+
+fn argon2(settings: &PasswordHashingSettings) -> Result<Argon2<'static>, VentError> {
+    let params = Params::new(
+        settings.argon2_memory_kib,
+        settings.argon2_iterations,
+        settings.argon2_parallelism,
+        None,
+    )?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+///Hashes a password using the algorithm from the settings.
+///
+/// This is deliberately slow, so it runs on the blocking thread pool rather than holding up the runtime.
+pub async fn hash_password(
+    settings: &PasswordHashingSettings,
+    unhashed_password: &str,
+) -> Result<String, VentError> {
+    let settings = settings.clone();
+    let unhashed_password = unhashed_password.to_string();
+
+    spawn_blocking(move || hash_password_blocking(&settings, &unhashed_password))
+        .await
+        .context(JoinSnafu {
+            title: ThreadReason::HashingPassword,
+        })?
+}
+
+fn hash_password_blocking(
+    settings: &PasswordHashingSettings,
+    unhashed_password: &str,
+) -> Result<String, VentError> {
+    Ok(match settings.algorithm {
+        HashingAlgorithm::Argon2id => {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2(settings)?
+                .hash_password(unhashed_password.as_bytes(), &salt)?
+                .to_string()
+        }
+        HashingAlgorithm::Bcrypt => bcrypt::hash(unhashed_password, settings.bcrypt_cost)?,
+    })
+}
+
+///Checks a password against a hash made by any of the supported algorithms, on the blocking thread pool like [`hash_password`]
+pub async fn verify_password(unhashed_password: &str, hash: &str) -> Result<bool, VentError> {
+    let unhashed_password = unhashed_password.to_string();
+    let hash = hash.to_string();
+
+    spawn_blocking(move || verify_password_blocking(&unhashed_password, &hash))
+        .await
+        .context(JoinSnafu {
+            title: ThreadReason::HashingPassword,
+        })?
+}
+
+fn verify_password_blocking(unhashed_password: &str, hash: &str) -> Result<bool, VentError> {
+    match HashingAlgorithm::of_hash(hash) {
+        Some(HashingAlgorithm::Argon2id) => {
+            let parsed = PasswordHash::new(hash)?;
+            //the parameters come from the hash itself, so the default instance is fine here
+            match Argon2::default().verify_password(unhashed_password.as_bytes(), &parsed) {
+                Ok(()) => Ok(true),
+                Err(argon2::password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        }
+        Some(HashingAlgorithm::Bcrypt) => Ok(bcrypt::verify(unhashed_password, hash)?),
+        None => {
+            warn!("Found a password hash from an unknown algorithm");
+            Ok(false)
+        }
+    }
+}
+
+///Whether a hash was made with a different algorithm or different parameters to the current settings, and so should be replaced
+pub fn needs_rehash(settings: &PasswordHashingSettings, hash: &str) -> bool {
+    match (settings.algorithm, HashingAlgorithm::of_hash(hash)) {
+        (HashingAlgorithm::Argon2id, Some(HashingAlgorithm::Argon2id)) => {
+            let Ok(params) = PasswordHash::new(hash).and_then(|x| Params::try_from(&x)) else {
+                return true;
+            };
+
+            params.m_cost() != settings.argon2_memory_kib
+                || params.t_cost() != settings.argon2_iterations
+                || params.p_cost() != settings.argon2_parallelism
+        }
+        (HashingAlgorithm::Bcrypt, Some(HashingAlgorithm::Bcrypt)) => {
+            hash.split('$')
+                .nth(2)
+                .and_then(|cost| cost.parse::<u32>().ok())
+                != Some(settings.bcrypt_cost)
+        }
+        _ => true,
+    }
+}
+
+///A list of very common passwords, which are the first thing anyone would try
+static COMMON_PASSWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    include_str!("common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect()
+});
+
+///Checks a new password against the policy from the settings, returning a message for each problem - an empty list means it's fine
+pub fn check_password_policy(
+    settings: &PasswordPolicySettings,
+    unhashed_password: &str,
+    personal_details: &[&str],
+) -> Vec<String> {
+    let mut problems = vec![];
+    let lowercase = unhashed_password.to_lowercase();
+
+    if unhashed_password.chars().count() < settings.min_length {
+        problems.push(format!(
+            "Passwords must be at least {} characters long.",
+            settings.min_length
+        ));
+    }
+
+    if settings.check_common && COMMON_PASSWORDS.contains(lowercase.as_str()) {
+        problems.push("That password is too common - pick something less guessable.".into());
+    }
+
+    if settings.check_personal_details
+        && personal_details
+            .iter()
+            .map(|x| x.trim().to_lowercase())
+            .filter(|x| x.chars().count() >= 3)
+            .any(|x| lowercase.contains(&x))
+    {
+        problems.push("Passwords can't contain your username or name.".into());
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicySettings {
+        PasswordPolicySettings::default()
+    }
+
+    ///Settings with the smallest costs each algorithm allows, so the tests stay quick
+    fn cheap_hashing(algorithm: HashingAlgorithm) -> PasswordHashingSettings {
+        PasswordHashingSettings {
+            algorithm,
+            argon2_memory_kib: Params::MIN_M_COST,
+            argon2_iterations: Params::MIN_T_COST,
+            argon2_parallelism: Params::MIN_P_COST,
+            bcrypt_cost: 4,
+        }
+    }
+
+    #[test]
+    fn common_passwords_meet_the_default_length() {
+        //anything shorter would already be turned away for its length, so it'd never be checked
+        let min_length = policy().min_length;
+        for password in COMMON_PASSWORDS.iter() {
+            assert!(
+                password.chars().count() >= min_length,
+                "{password:?} is too short to ever be checked"
+            );
+        }
+    }
+
+    #[test]
+    fn good_password_has_no_problems() {
+        assert!(check_password_policy(&policy(), "correct horse battery", &["jsmith"]).is_empty());
+    }
+
+    #[test]
+    fn short_password() {
+        let problems = check_password_policy(&policy(), "tR0ub4d&", &[]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("at least 10"), "{problems:?}");
+    }
+
+    #[test]
+    fn common_password_ignores_case() {
+        let problems = check_password_policy(&policy(), "QwertyUIOP", &[]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("too common"), "{problems:?}");
+    }
+
+    #[test]
+    fn personal_details() {
+        let problems = check_password_policy(&policy(), "hello jsmith 2024", &["JSmith", "Jo"]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("username or name"), "{problems:?}");
+
+        //really short details would rule out far too much
+        assert!(check_password_policy(&policy(), "jo likes long walks", &["Jo"]).is_empty());
+    }
+
+    #[test]
+    fn checks_can_be_turned_off() {
+        let settings = PasswordPolicySettings {
+            min_length: 4,
+            check_common: false,
+            check_personal_details: false,
+        };
+        assert!(check_password_policy(&settings, "qwertyuiop", &[]).is_empty());
+        assert!(check_password_policy(&settings, "jsmith1234", &["jsmith"]).is_empty());
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let problems = check_password_policy(&policy(), "jsmith", &["jsmith"]);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+    }
+
+    #[test]
+    fn hashes_verify() {
+        for algorithm in [HashingAlgorithm::Argon2id, HashingAlgorithm::Bcrypt] {
+            let hash = hash_password_blocking(&cheap_hashing(algorithm), "hunter2hunter2").unwrap();
+            assert_eq!(HashingAlgorithm::of_hash(&hash), Some(algorithm));
+            assert!(verify_password_blocking("hunter2hunter2", &hash).unwrap());
+            assert!(!verify_password_blocking("hunter3hunter3", &hash).unwrap());
+        }
+    }
+
+    #[test]
+    fn matching_hashes_dont_need_rehashing() {
+        for algorithm in [HashingAlgorithm::Argon2id, HashingAlgorithm::Bcrypt] {
+            let settings = cheap_hashing(algorithm);
+            let hash = hash_password_blocking(&settings, "hunter2hunter2").unwrap();
+            assert!(!needs_rehash(&settings, &hash), "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn changed_parameters_need_rehashing() {
+        let settings = cheap_hashing(HashingAlgorithm::Argon2id);
+        let hash = hash_password_blocking(&settings, "hunter2hunter2").unwrap();
+        assert!(needs_rehash(
+            &PasswordHashingSettings {
+                argon2_iterations: settings.argon2_iterations + 1,
+                ..settings.clone()
+            },
+            &hash
+        ));
+        assert!(needs_rehash(
+            &PasswordHashingSettings {
+                argon2_memory_kib: settings.argon2_memory_kib * 2,
+                ..settings
+            },
+            &hash
+        ));
+
+        let settings = cheap_hashing(HashingAlgorithm::Bcrypt);
+        let hash = hash_password_blocking(&settings, "hunter2hunter2").unwrap();
+        assert!(needs_rehash(
+            &PasswordHashingSettings {
+                bcrypt_cost: settings.bcrypt_cost + 1,
+                ..settings
+            },
+            &hash
+        ));
+    }
+
+    #[test]
+    fn changed_algorithm_needs_rehashing() {
+        let bcrypt_hash =
+            hash_password_blocking(&cheap_hashing(HashingAlgorithm::Bcrypt), "hunter2hunter2")
+                .unwrap();
+        assert!(needs_rehash(
+            &cheap_hashing(HashingAlgorithm::Argon2id),
+            &bcrypt_hash
+        ));
+
+        let argon2_hash =
+            hash_password_blocking(&cheap_hashing(HashingAlgorithm::Argon2id), "hunter2hunter2")
+                .unwrap();
+        assert!(needs_rehash(
+            &cheap_hashing(HashingAlgorithm::Bcrypt),
+            &argon2_hash
+        ));
+    }
+
+    #[test]
+    fn unknown_hashes_need_rehashing() {
+        assert!(needs_rehash(
+            &PasswordHashingSettings::default(),
+            "$1$saltsalt$notarealhash"
+        ));
+        assert!(!verify_password_blocking("anything", "$1$saltsalt$notarealhash").unwrap());
+    }
+}
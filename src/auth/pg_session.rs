@@ -1,15 +1,23 @@
-use crate::error::{ComponentRangeSnafu, SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu};
+use crate::{
+    auth::{sessions::SESSION_IP_KEY, tokens::hash_token},
+    error::{ComponentRangeSnafu, SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu},
+};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
-use serde_json::from_slice;
+use serde_json::{from_slice, Value};
 use snafu::ResultExt;
 use sqlx::{Pool, Postgres};
+use std::time::Duration;
 use time::OffsetDateTime;
+use tokio::sync::broadcast::Receiver as BroadcastReceiver;
 use tower_sessions::{
     session::{Id, Record},
     session_store::Error as SSError,
     ExpiredDeletion, SessionStore,
 };
 
+///Where `axum-login` keeps the logged in user's ID in the session
+const AXUM_LOGIN_DATA_KEY: &str = "axum-login.data";
+
 #[derive(Clone, Debug)]
 pub struct PostgresStore {
     pool: Pool<Postgres>,
@@ -19,6 +27,99 @@ impl PostgresStore {
     pub fn new(pool: Pool<Postgres>) -> Self {
         Self { pool }
     }
+
+    ///Writes a session to the database - only `create` adds new rows, so that a request which was already in flight can't bring back a session that just got revoked
+    async fn write(&self, session: &Record, is_new: bool) -> Result<(), SSError> {
+        let session_data = serde_json::to_vec(&session.data).context(SerdeJsonSnafu {
+            action: SerdeJsonAction::SessionSerde,
+        })?;
+        let session_id = &session.id.to_string();
+        let session_expiry = session.expiry_date;
+        let session_expiry = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(
+                session_expiry.year(),
+                session_expiry.month() as u32,
+                session_expiry.day() as u32,
+            )
+            .expect("poorly formatted date from OffsetDateTime"),
+            NaiveTime::from_hms_opt(
+                session_expiry.hour() as u32,
+                session_expiry.minute() as u32,
+                session_expiry.second() as u32,
+            )
+            .expect("poorly formatted time from OffsetDateTime"),
+        );
+
+        //kept in their own columns so that people can see and revoke their sessions
+        let person_id = session
+            .data
+            .get(AXUM_LOGIN_DATA_KEY)
+            .and_then(|x| x.get("user_id"))
+            .and_then(Value::as_i64)
+            .map(|x| x as i32);
+        let ip = session.data.get(SESSION_IP_KEY).and_then(Value::as_str);
+
+        if is_new {
+            sqlx::query!(
+                r#"
+        INSERT INTO public.sessions (id, data, expiry_date, public_id, person_id, ip)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+                session_id,
+                session_data,
+                session_expiry,
+                hash_token(session_id),
+                person_id,
+                ip
+            )
+            .execute(&self.pool)
+            .await
+        } else {
+            sqlx::query!(
+                r#"
+        UPDATE public.sessions
+        SET data = $2, expiry_date = $3, person_id = $4, ip = $5, last_seen = now()
+        WHERE id = $1
+        "#,
+                session_id,
+                session_data,
+                session_expiry,
+                person_id,
+                ip
+            )
+            .execute(&self.pool)
+            .await
+        }
+        .context(SqlxSnafu {
+            action: SqlxAction::AddingSession,
+        })?;
+
+        Ok(())
+    }
+}
+
+///Regularly deletes sessions which have expired, so that the table doesn't grow forever
+pub fn delete_expired_sessions_thread(
+    store: PostgresStore,
+    mut stop_rx: BroadcastReceiver<()>,
+    period: Duration,
+) {
+    tokio::spawn(async move {
+        loop {
+            match store.delete_expired().await {
+                Ok(()) => debug!("Deleted expired sessions"),
+                Err(e) => error!(?e, "Error deleting expired sessions"),
+            }
+
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Old sessions thread stopping");
+                    return;
+                },
+                () = tokio::time::sleep(period) => {},
+            }
+        }
+    });
 }
 
 #[async_trait]
@@ -55,51 +156,11 @@ impl SessionStore for PostgresStore {
 
         session.id = session_id;
 
-        self.save(session).await
+        self.write(session, true).await
     }
 
     async fn save(&self, session: &Record) -> Result<(), SSError> {
-        let session_data = serde_json::to_vec(&session.data).context(SerdeJsonSnafu {
-            action: SerdeJsonAction::SessionSerde,
-        })?;
-        let session_id = &session.id.to_string();
-        let session_expiry = session.expiry_date;
-        let session_expiry = NaiveDateTime::new(
-            NaiveDate::from_ymd_opt(
-                session_expiry.year(),
-                session_expiry.month() as u32,
-                session_expiry.day() as u32,
-            )
-            .expect("poorly formatted date from OffsetDateTime"),
-            NaiveTime::from_hms_opt(
-                session_expiry.hour() as u32,
-                session_expiry.minute() as u32,
-                session_expiry.second() as u32,
-            )
-            .expect("poorly formatted time from OffsetDateTime"),
-        );
-
-        sqlx::query!(
-            r#"
-        INSERT INTO public.sessions (id, data, expiry_date)
-        VALUES ($1, $2, $3)
-        on conflict (id) do update
-            set
-              data = excluded.data,
-              expiry_date = excluded.expiry_date
-
-        "#,
-            session_id,
-            session_data,
-            session_expiry.into()
-        )
-        .execute(&self.pool)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::AddingSession,
-        })?;
-
-        Ok(())
+        self.write(session, false).await
     }
 
     async fn load(&self, id: &Id) -> Result<Option<Record>, SSError> {
@@ -107,7 +168,7 @@ impl SessionStore for PostgresStore {
 
         let session_id = id.to_string();
         let rec = sqlx::query!(
-            "SELECT * FROM sessions WHERE id = $1 and expiry_date > now()",
+            "SELECT data, expiry_date FROM sessions WHERE id = $1 and expiry_date > now()",
             session_id
         )
         .fetch_optional(&mut *self.pool.acquire().await.context(SqlxSnafu {
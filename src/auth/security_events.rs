@@ -0,0 +1,195 @@
+//! Module that keeps a permanent record of security-relevant events (logins, lockouts, password and role changes) in `security_events`, and lets developers search through it.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        client_ip::GrabRemoteIP,
+        get_auth_object, PermissionsTarget,
+    },
+    error::{EncodeStep, ParseTimeSnafu, SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::{compile_with_newtitle, CustomFormat},
+    state::VentState,
+};
+use axum::{
+    extract::{FromRequestParts, Query, State},
+    http::{header::USER_AGENT, request::Parts},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use axum_login::permission_required;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::convert::Infallible;
+
+///The most events to show on one page
+const MAX_EVENTS_SHOWN: i64 = 500;
+
+#[derive(Debug, Copy, Clone, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum SecurityEventKind {
+    LoginSucceeded,
+    ///The password (or single sign-on etc.) was right, but they still need to pass the second factor
+    SecondFactorRequired,
+    LoginFailed,
+    LockedOut,
+    PasswordChanged,
+    PasswordResetSent,
+    RoleChanged,
+}
+
+///Where a request came from, for recording alongside security events
+#[derive(Debug, Clone)]
+pub struct RequestDetails {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<VentState> for RequestDetails {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &VentState,
+    ) -> Result<Self, Self::Rejection> {
+        let ip = GrabRemoteIP::from_request_parts(parts, state)
+            .await
+            .ok()
+            .map(|x| x.as_str().to_string());
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|x| x.to_str().ok())
+            .map(ToString::to_string);
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+///Adds an event to the security log - `username` is who the event is about, and `details` is anything else worth knowing (eg. who did it, if it wasn't them)
+pub async fn record_security_event(
+    state: &VentState,
+    kind: SecurityEventKind,
+    username: &str,
+    details: Option<String>,
+    request: &RequestDetails,
+) -> Result<(), VentError> {
+    let kind: &'static str = kind.into();
+
+    sqlx::query!(
+        r#"
+INSERT INTO security_events (kind, username, person_id, ip, user_agent, details)
+VALUES ($1, $2, (SELECT id FROM people WHERE LOWER(username) = LOWER($2)), $3, $4, $5)
+        "#,
+        kind,
+        username,
+        request.ip,
+        request.user_agent,
+        details
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingSecurityEvent,
+    })?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SecurityEventsFilter {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+}
+
+fn parse_filter_date(date: &str) -> Result<Option<NaiveDate>, VentError> {
+    if date.trim().is_empty() {
+        return Ok(None);
+    }
+
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .context(ParseTimeSnafu {
+            original: date.to_string(),
+            how_got_in: EncodeStep::Decode,
+        })
+        .map(Some)
+}
+
+#[axum::debug_handler]
+async fn get_security_events(
+    auth: Auth,
+    State(state): State<VentState>,
+    Query(SecurityEventsFilter { username, from, to }): Query<SecurityEventsFilter>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct SecurityEvent {
+        kind: String,
+        username: String,
+        ip: Option<String>,
+        user_agent: Option<String>,
+        details: Option<String>,
+        happened_at: String,
+    }
+
+    let from_date = parse_filter_date(&from)?;
+    let to_date = parse_filter_date(&to)?;
+    let username_filter = Some(username.trim()).filter(|x| !x.is_empty());
+
+    let format = &state.settings.niche.date_time_format;
+    let events: Vec<SecurityEvent> = sqlx::query!(
+        r#"
+SELECT kind, username, ip, user_agent, details, happened_at
+FROM security_events
+WHERE ($1::TEXT IS NULL OR LOWER(username) = LOWER($1))
+AND ($2::DATE IS NULL OR happened_at >= $2)
+AND ($3::DATE IS NULL OR happened_at < $3 + 1)
+ORDER BY happened_at DESC
+LIMIT $4
+        "#,
+        username_filter,
+        from_date,
+        to_date,
+        MAX_EVENTS_SHOWN
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingSecurityEvents,
+    })?
+    .into_iter()
+    .map(|rec| SecurityEvent {
+        kind: rec.kind,
+        username: rec.username,
+        ip: rec.ip,
+        user_agent: rec.user_agent,
+        details: rec.details,
+        happened_at: rec.happened_at.to_env_string(format),
+    })
+    .collect();
+
+    let aa = get_auth_object(auth).await?;
+
+    compile_with_newtitle(
+        "www/security_events.liquid",
+        liquid::object!({ "auth": aa, "events": events, "max_shown": MAX_EVENTS_SHOWN, "filter": { "username": username, "from": from, "to": to } }),
+        &state.settings.brand.instance_name,
+        Some("Security Events".into()),
+    )
+    .await
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/security_events", get(get_security_events))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+}
@@ -0,0 +1,230 @@
+//! Module that lets people see and revoke where they're logged in, and lets admins log people out everywhere.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        client_ip::GrabRemoteIP,
+        tokens::hash_token,
+        PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Form, Router,
+};
+use axum_login::{login_required, permission_required};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+///The session key for the IP that a session was last used from
+pub const SESSION_IP_KEY: &str = "vent.ip";
+///The session key for when a session was last used, as a unix timestamp
+const SESSION_LAST_SEEN_KEY: &str = "vent.last_seen";
+///How often to write down that a session has been used - every request would mean a database write per request
+const LAST_SEEN_GRANULARITY_SECS: i64 = 5 * 60;
+
+///Middleware which keeps the IP and last-seen time of logged in sessions up to date
+///
+/// Needs to sit outside the API token middleware, so that requests using tokens don't get sessions made for them
+pub async fn session_metadata_middleware(
+    auth: Auth,
+    session: Session,
+    remote_ip: Option<GrabRemoteIP>,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    if auth.user.is_some() {
+        let now = Utc::now().timestamp();
+        let last_seen: Option<i64> = session
+            .get(SESSION_LAST_SEEN_KEY)
+            .await
+            .context(TowerSessionsSnafu)?;
+        if last_seen.is_none_or(|x| now - x > LAST_SEEN_GRANULARITY_SECS) {
+            session
+                .insert(SESSION_LAST_SEEN_KEY, now)
+                .await
+                .context(TowerSessionsSnafu)?;
+        }
+
+        if let Some(ip) = remote_ip.as_ref().map(GrabRemoteIP::as_str) {
+            let old_ip: Option<String> = session
+                .get(SESSION_IP_KEY)
+                .await
+                .context(TowerSessionsSnafu)?;
+            if old_ip.as_deref() != Some(ip) {
+                session
+                    .insert(SESSION_IP_KEY, ip)
+                    .await
+                    .context(TowerSessionsSnafu)?;
+            }
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[derive(Serialize)]
+pub struct ActiveSession {
+    pub public_id: String,
+    pub created_at: String,
+    pub last_seen: String,
+    pub ip: Option<String>,
+    pub current: bool,
+}
+
+///Gets all of someone's sessions which haven't expired, newest first
+pub async fn get_active_sessions(
+    state: &VentState,
+    person_id: i32,
+    current: Option<&Session>,
+) -> Result<Vec<ActiveSession>, VentError> {
+    let format = &state.settings.niche.date_time_format;
+    let current_public_id = current
+        .and_then(Session::id)
+        .map(|x| hash_token(&x.to_string()));
+
+    Ok(sqlx::query!(
+        r#"
+SELECT public_id as "public_id!", created_at, last_seen, ip
+FROM sessions
+WHERE person_id = $1 AND expiry_date > now() AND public_id IS NOT NULL
+ORDER BY last_seen DESC
+        "#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingSessions(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| ActiveSession {
+        current: current_public_id.as_ref() == Some(&rec.public_id),
+        public_id: rec.public_id,
+        created_at: rec.created_at.to_env_string(format),
+        last_seen: rec.last_seen.to_env_string(format),
+        ip: rec.ip,
+    })
+    .collect())
+}
+
+#[derive(Deserialize)]
+struct RevokeSession {
+    public_id: String,
+}
+
+#[axum::debug_handler]
+async fn post_revoke_session(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(RevokeSession { public_id }): Form<RevokeSession>,
+) -> Result<impl IntoResponse, VentError> {
+    let person_id = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to revoke a session")
+        .id;
+
+    let is_current = session
+        .id()
+        .is_some_and(|x| hash_token(&x.to_string()) == public_id);
+    if is_current {
+        info!(%person_id, "Revoking current session");
+        auth.logout().await?;
+        return Ok(Redirect::to("/"));
+    }
+
+    info!(%person_id, "Revoking session");
+
+    sqlx::query!(
+        "DELETE FROM sessions WHERE public_id = $1 AND person_id = $2",
+        public_id,
+        person_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingSessions(person_id.into()),
+    })?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+#[axum::debug_handler]
+async fn post_revoke_other_sessions(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let person_id = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to revoke sessions")
+        .id;
+    let current_id = session.id().map(|x| x.to_string());
+
+    info!(%person_id, "Revoking all other sessions");
+
+    sqlx::query!(
+        "DELETE FROM sessions WHERE person_id = $1 AND id IS DISTINCT FROM $2",
+        person_id,
+        current_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingSessions(person_id.into()),
+    })?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+#[derive(Deserialize)]
+struct ForceLogout {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_force_logout(
+    mut auth: Auth,
+    State(state): State<VentState>,
+    Form(ForceLogout { id }): Form<ForceLogout>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Forcing logout everywhere");
+
+    sqlx::query!("DELETE FROM sessions WHERE person_id = $1", id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingSessions(id.into()),
+        })?;
+
+    if auth.user.as_ref().is_some_and(|x| x.id == id) {
+        auth.logout().await?;
+        return Ok(Redirect::to("/"));
+    }
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/force_logout", post(post_force_logout))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::EditPeople
+        ))
+        .route("/sessions/revoke", post(post_revoke_session))
+        .route("/sessions/revoke_others", post(post_revoke_other_sessions))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
@@ -0,0 +1,308 @@
+//! Module that deals with slowing down and locking out repeated failed logins, both per-username and per-IP.
+
+use crate::{
+    auth::{backend::VentAuthBackend, PermissionsTarget},
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::post,
+    Form, Router,
+};
+use axum_login::permission_required;
+use serde::Deserialize;
+use snafu::ResultExt;
+use sqlx::Connection;
+
+///Checks whether either the username or the IP is currently locked out
+pub async fn is_locked_out(state: &VentState, username: &str, ip: &str) -> Result<bool, VentError> {
+    let mut conn = state.get_connection().await?;
+
+    let account_locked = sqlx::query!(
+        r#"SELECT locked_until > now() as "locked!" FROM people WHERE LOWER(username) = LOWER($1) AND locked_until IS NOT NULL"#,
+        username
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingLoginAttempts,
+    })?
+    .is_some_and(|rec| rec.locked);
+
+    if account_locked {
+        return Ok(true);
+    }
+
+    let throttle = &state.settings.auth.throttle;
+    let ip_failures = sqlx::query!(
+        r#"SELECT COUNT(*) as "count!" FROM login_attempts WHERE ip = $1 AND NOT succeeded AND attempted_at > (now() - make_interval(mins => $2))"#,
+        ip,
+        throttle.window_minutes
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingLoginAttempts,
+    })?
+    .count;
+
+    Ok(ip_failures >= throttle.ip_lockout_after)
+}
+
+///What to do with a login attempt, from [`start_attempt`]
+#[derive(Debug, Copy, Clone)]
+pub enum LoginAttempt {
+    ///Go ahead and check their details - the attempt has already been counted as a failure, so it needs finishing with [`record_success`], [`record_failure`] or [`forget_attempt`]
+    Allowed {
+        attempt_id: i32,
+    },
+    LockedOut,
+    ///There have been a few failures recently, so they have to wait a bit before trying again
+    TooSoon,
+}
+
+///Checks whether someone can try to log in, and if so counts the attempt as a failure until we know otherwise
+///
+/// The check and the count happen in one statement whilst holding locks for the username and IP, so that lots of attempts sent at once can't all get through before any of them are counted
+pub async fn start_attempt(
+    state: &VentState,
+    username: &str,
+    ip: &str,
+) -> Result<LoginAttempt, VentError> {
+    let throttle = &state.settings.auth.throttle;
+    let mut conn = state.get_connection().await?;
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    //always taken in the same order, so that two attempts can't each be waiting on the other's lock
+    sqlx::query!(
+        r#"
+SELECT COUNT(*) as "locks!" FROM (
+    SELECT pg_advisory_xact_lock(k) FROM (
+        SELECT hashtext(x) AS k FROM (VALUES ($1::TEXT), ($2::TEXT)) v(x) ORDER BY k
+    ) keys
+) locks
+        "#,
+        format!("login-username:{}", username.to_lowercase()),
+        format!("login-ip:{ip}")
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingLoginAttempts,
+    })?;
+
+    let attempt = sqlx::query!(
+        r#"
+WITH counts AS (
+    SELECT
+        COUNT(*) FILTER (WHERE LOWER(username) = LOWER($1)) AS username_failures,
+        COUNT(*) FILTER (WHERE ip = $2) AS ip_failures,
+        MAX(attempted_at) AS last_failure
+    FROM login_attempts
+    WHERE NOT succeeded AND attempted_at > (now() - make_interval(mins => $3)) AND (LOWER(username) = LOWER($1) OR ip = $2)
+)
+INSERT INTO login_attempts (username, ip, succeeded)
+SELECT $1, $2, false
+FROM counts
+WHERE NOT EXISTS (SELECT 1 FROM people WHERE LOWER(username) = LOWER($1) AND locked_until > now())
+    AND ip_failures < $4
+    AND (
+        GREATEST(username_failures, ip_failures) <= $5::BIGINT
+        OR last_failure <= now() - make_interval(secs => LEAST(power(2, GREATEST(username_failures, ip_failures) - $5::BIGINT), $6::BIGINT))
+    )
+RETURNING id
+        "#,
+        username,
+        ip,
+        throttle.window_minutes,
+        throttle.ip_lockout_after,
+        throttle.delay_after,
+        i64::try_from(throttle.max_delay_secs).unwrap_or(i64::MAX)
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingLoginAttempt,
+    })?;
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(match attempt {
+        Some(rec) => LoginAttempt::Allowed { attempt_id: rec.id },
+        //only for picking which message to show - the insert above is what actually stops them
+        None if is_locked_out(state, username, ip).await? => LoginAttempt::LockedOut,
+        None => LoginAttempt::TooSoon,
+    })
+}
+
+///Records that an attempt from [`start_attempt`] failed, locking the account if there have been too many
+///
+/// Returns whether this failure locked the account
+pub async fn record_failure(state: &VentState, username: &str) -> Result<bool, VentError> {
+    let throttle = &state.settings.auth.throttle;
+    let mut conn = state.get_connection().await?;
+
+    sqlx::query!(
+        "DELETE FROM login_attempts WHERE attempted_at < (now() - make_interval(mins => $1))",
+        throttle.window_minutes
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingLoginAttempt,
+    })?;
+
+    let locked = sqlx::query!(
+        r#"
+UPDATE people
+SET locked_until = now() + make_interval(mins => $2)
+WHERE LOWER(username) = LOWER($1)
+    AND (locked_until IS NULL OR locked_until <= now())
+    AND (SELECT COUNT(*) FROM login_attempts WHERE LOWER(username) = LOWER($1) AND NOT succeeded AND attempted_at > (now() - make_interval(mins => $3))) >= $4
+RETURNING id
+        "#,
+        username,
+        throttle.lockout_minutes,
+        throttle.window_minutes,
+        throttle.lockout_after
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(username.to_string().into()),
+    })?
+    .is_some();
+
+    if locked {
+        warn!(?username, "Locked account after too many failed logins");
+    }
+
+    Ok(locked)
+}
+
+///Records that an attempt from [`start_attempt`] worked, which forgets about any earlier failures for that username
+pub async fn record_success(
+    state: &VentState,
+    username: &str,
+    attempt_id: i32,
+) -> Result<(), VentError> {
+    let mut conn = state.get_connection().await?;
+
+    sqlx::query!(
+        "UPDATE login_attempts SET succeeded = true WHERE id = $1",
+        attempt_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingLoginAttempt,
+    })?;
+
+    sqlx::query!(
+        "DELETE FROM login_attempts WHERE LOWER(username) = LOWER($1) AND NOT succeeded",
+        username
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingLoginAttempt,
+    })?;
+
+    Ok(())
+}
+
+///Forgets about an attempt from [`start_attempt`] which was neither right nor wrong, eg. because they haven't set a password yet
+pub async fn forget_attempt(state: &VentState, attempt_id: i32) -> Result<(), VentError> {
+    sqlx::query!("DELETE FROM login_attempts WHERE id = $1", attempt_id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::AddingLoginAttempt,
+        })?;
+
+    Ok(())
+}
+
+///Gets how many recent failures a person has, and until when they're locked (formatted), for `/edit_person`
+pub async fn get_lockout_status(
+    state: &VentState,
+    person_id: i32,
+) -> Result<(i64, Option<String>), VentError> {
+    let rec = sqlx::query!(
+        r#"
+SELECT
+    CASE WHEN p.locked_until > now() THEN p.locked_until ELSE NULL END as locked_until,
+    (SELECT COUNT(*) FROM login_attempts la WHERE LOWER(la.username) = LOWER(p.username) AND NOT la.succeeded AND la.attempted_at > (now() - make_interval(mins => $2))) as "failures!"
+FROM people p
+WHERE p.id = $1
+        "#,
+        person_id,
+        state.settings.auth.throttle.window_minutes
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingLoginAttempts,
+    })?;
+
+    Ok((
+        rec.failures,
+        rec.locked_until
+            .map(|x| x.to_env_string(&state.settings.niche.date_time_format)),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ClearLockout {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_clear_lockout(
+    State(state): State<VentState>,
+    Form(ClearLockout { id }): Form<ClearLockout>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Clearing lockout");
+
+    let mut conn = state.get_connection().await?;
+
+    let username = sqlx::query!(
+        "UPDATE people SET locked_until = NULL WHERE id = $1 RETURNING username",
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(id.into()),
+    })?
+    .username;
+
+    sqlx::query!(
+        "DELETE FROM login_attempts WHERE LOWER(username) = LOWER($1) AND NOT succeeded",
+        username
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingLoginAttempt,
+    })?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/clear_lockout", post(post_clear_lockout))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::EditPeople
+        ))
+}
@@ -0,0 +1,306 @@
+//! Module for the random tokens that get sent out in one-time links (eg. for logging in or setting a password) - only the hashes ever get stored in the database.
+
+use crate::{
+    auth::PermissionsRole,
+    error::{SqlxAction, SqlxSnafu, VentError},
+    state::{
+        mail::{EmailKind, EmailToSend},
+        VentState,
+    },
+};
+use rand::{thread_rng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use sqlx::{pool::PoolConnection, PgConnection, Postgres};
+use std::future::Future;
+use subtle::ConstantTimeEq;
+
+///Generates a new random token, returning it alongside its hash for the database
+pub fn generate_token() -> (String, String) {
+    let mut bytes = [0_u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+
+    let token = hex::encode(bytes);
+    let hashed = hash_token(&token);
+    (token, hashed)
+}
+
+///Hashes a token for storage or lookup
+///
+/// Tokens already have plenty of entropy, so a fast hash is fine here (unlike passwords)
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
+
+///Checks whether a secret someone sent matches the one we expected
+///
+/// This takes the same time however much of it was right, so that timing it can't be used to guess the secret bit by bit
+pub fn secrets_match(expected: &str, sent: &str) -> bool {
+    expected
+        .trim()
+        .as_bytes()
+        .ct_eq(sent.trim().as_bytes())
+        .into()
+}
+
+///What a one-time link is for - each has its own table, but they work the same way
+#[derive(Debug, Copy, Clone)]
+pub enum TokenPurpose {
+    ///`login_tokens`, for logging in without a password
+    Login,
+    ///`password_tokens`, for setting a new password
+    SetPassword,
+}
+
+impl TokenPurpose {
+    fn finding_action(self, person_id: i32) -> SqlxAction {
+        match self {
+            Self::Login => SqlxAction::FindingLoginToken(person_id.into()),
+            Self::SetPassword => SqlxAction::FindingPasswordToken(person_id.into()),
+        }
+    }
+
+    fn updating_action(self, person_id: i32) -> SqlxAction {
+        match self {
+            Self::Login => SqlxAction::UpdatingLoginToken(person_id.into()),
+            Self::SetPassword => SqlxAction::UpdatingPasswordToken(person_id.into()),
+        }
+    }
+}
+
+///The `code` query parameter or form field from a one-time link
+#[derive(Debug, Deserialize)]
+pub struct Link {
+    #[serde(default)]
+    pub code: String,
+}
+
+///Whether a one-time link works, and why not if it doesn't
+#[derive(Debug, Copy, Clone)]
+pub enum TokenStatus {
+    Valid { token_id: i32 },
+    Missing,
+    Wrong,
+    Expired,
+    Used,
+}
+
+impl TokenStatus {
+    pub fn failure_url(self) -> Option<&'static str> {
+        match self {
+            Self::Valid { .. } => None,
+            Self::Missing => Some("/login_failure/no_numbers"),
+            Self::Wrong => Some("/login_failure/failed_numbers"),
+            Self::Expired => Some("/login_failure/expired_link"),
+            Self::Used => Some("/login_failure/used_link"),
+        }
+    }
+}
+
+///Checks whether a link would work, without using it up
+pub async fn check_token(
+    state: &VentState,
+    purpose: TokenPurpose,
+    person_id: i32,
+    code: &str,
+) -> Result<TokenStatus, VentError> {
+    struct Found {
+        id: i32,
+        is_expired: bool,
+        is_used: bool,
+    }
+
+    if code.trim().is_empty() {
+        return Ok(TokenStatus::Missing);
+    }
+
+    let mut conn = state.get_connection().await?;
+    let token_hash = hash_token(code);
+    let found = match purpose {
+        TokenPurpose::Login => sqlx::query_as!(
+            Found,
+            r#"SELECT id, expires_at < now() as "is_expired!", used_at IS NOT NULL as "is_used!" FROM login_tokens WHERE person_id = $1 AND token_hash = $2"#,
+            person_id,
+            token_hash
+        )
+        .fetch_optional(&mut *conn)
+        .await,
+        TokenPurpose::SetPassword => sqlx::query_as!(
+            Found,
+            r#"SELECT id, expires_at < now() as "is_expired!", used_at IS NOT NULL as "is_used!" FROM password_tokens WHERE person_id = $1 AND token_hash = $2"#,
+            person_id,
+            token_hash
+        )
+        .fetch_optional(&mut *conn)
+        .await,
+    }
+    .context(SqlxSnafu {
+        action: purpose.finding_action(person_id),
+    })?;
+
+    Ok(match found {
+        None => TokenStatus::Wrong,
+        Some(token) if token.is_used => TokenStatus::Used,
+        Some(token) if token.is_expired => TokenStatus::Expired,
+        Some(token) => TokenStatus::Valid { token_id: token.id },
+    })
+}
+
+///Uses up a link from [`check_token`] - returns `false` if it has been used or has expired since then, eg. because the same link was submitted twice at once
+pub async fn consume_token(
+    state: &VentState,
+    purpose: TokenPurpose,
+    person_id: i32,
+    token_id: i32,
+) -> Result<bool, VentError> {
+    let mut conn = state.get_connection().await?;
+    let used = match purpose {
+        TokenPurpose::Login => sqlx::query!(
+            "UPDATE login_tokens SET used_at = now() WHERE id = $1 AND person_id = $2 AND used_at IS NULL AND expires_at > now()",
+            token_id,
+            person_id
+        )
+        .execute(&mut *conn)
+        .await,
+        TokenPurpose::SetPassword => sqlx::query!(
+            "UPDATE password_tokens SET used_at = now() WHERE id = $1 AND person_id = $2 AND used_at IS NULL AND expires_at > now()",
+            token_id,
+            person_id
+        )
+        .execute(&mut *conn)
+        .await,
+    }
+    .context(SqlxSnafu {
+        action: purpose.updating_action(person_id),
+    })?;
+
+    Ok(used.rows_affected() == 1)
+}
+
+///Makes a new link for someone, so that only the newest one works
+///
+/// Only called by the mail thread just before sending, so that the raw token never gets stored anywhere
+pub async fn make_token(
+    conn: &mut PgConnection,
+    purpose: TokenPurpose,
+    user_id: i32,
+    token_minutes: i32,
+) -> Result<String, VentError> {
+    let (token, token_hash) = generate_token();
+
+    match purpose {
+        TokenPurpose::Login => {
+            sqlx::query!(
+                "DELETE FROM login_tokens WHERE person_id = $1 AND used_at IS NULL",
+                user_id
+            )
+            .execute(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: purpose.updating_action(user_id),
+            })?;
+            sqlx::query!(
+                "INSERT INTO login_tokens (person_id, token_hash, expires_at) VALUES ($1, $2, now() + make_interval(mins => $3))",
+                user_id,
+                token_hash,
+                token_minutes
+            )
+            .execute(&mut *conn)
+            .await
+        }
+        TokenPurpose::SetPassword => {
+            sqlx::query!(
+                "DELETE FROM password_tokens WHERE person_id = $1 AND used_at IS NULL",
+                user_id
+            )
+            .execute(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: purpose.updating_action(user_id),
+            })?;
+            sqlx::query!(
+                "INSERT INTO password_tokens (person_id, token_hash, expires_at) VALUES ($1, $2, now() + make_interval(mins => $3))",
+                user_id,
+                token_hash,
+                token_minutes
+            )
+            .execute(&mut *conn)
+            .await
+        }
+    }
+    .context(SqlxSnafu {
+        action: purpose.updating_action(user_id),
+    })?;
+
+    Ok(token)
+}
+
+///Makes the email to send someone a new link in - the link itself gets made by [`make_token`] when the email is sent
+pub async fn get_email_for_token(
+    mut connection: PoolConnection<Postgres>,
+    purpose: TokenPurpose,
+    user_id: i32,
+    token_minutes: i32,
+) -> Result<EmailToSend, VentError> {
+    let person = sqlx::query!(
+        "SELECT username, first_name, surname FROM people WHERE id = $1",
+        user_id
+    )
+    .fetch_one(&mut *connection)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(user_id.into()),
+    })?;
+
+    Ok(EmailToSend {
+        to_id: user_id,
+        to_username: person.username,
+        to_fullname: format!("{} {}", person.first_name, person.surname),
+        kind: match purpose {
+            TokenPurpose::Login => EmailKind::LoginLink { token_minutes },
+            TokenPurpose::SetPassword => EmailKind::SetPassword { token_minutes },
+        },
+    })
+}
+
+///Someone who asked for a link to be emailed to them
+pub struct LinkRequester {
+    pub id: i32,
+    pub permissions: PermissionsRole,
+}
+
+///Finds someone by username and then runs `send` for them, all in the background - so that the response (and how long it takes) doesn't give away whether the username exists
+///
+/// `what` is what they asked for, for the logs
+pub fn send_link_in_background<F, Fut>(
+    state: VentState,
+    username: String,
+    what: &'static str,
+    send: F,
+) where
+    F: FnOnce(VentState, LinkRequester) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let person = match state.get_connection().await {
+            Ok(mut conn) => sqlx::query_as!(
+                LinkRequester,
+                r#"SELECT id, permissions as "permissions: PermissionsRole" FROM people WHERE LOWER(username) = LOWER($1)"#,
+                username
+            )
+            .fetch_optional(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingPerson(username.clone().into()),
+            }),
+            Err(e) => Err(e),
+        };
+
+        match person {
+            Ok(Some(person)) => send(state, person).await,
+            Ok(None) => warn!(?username, what, "Link asked for with an unknown username"),
+            Err(e) => error!(?e, what, "Error finding person to send link to"),
+        }
+    });
+}
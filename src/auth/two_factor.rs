@@ -0,0 +1,663 @@
+//! Module that deals with TOTP two-factor authentication - enrolment on `/edit_user`, the second step after a password login, and recovery codes.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        client_ip::GrabRemoteIP,
+        get_auth_object,
+        login::login_failed,
+        password::{hash_password, verify_password},
+        security_events::{record_security_event, RequestDetails, SecurityEventKind},
+        throttle::{record_success, start_attempt, LoginAttempt},
+        tokens::secrets_match,
+        PermissionsTarget,
+    },
+    error::{
+        SqlxAction, SqlxSnafu, TotpQrSnafu, TotpSecretSnafu, TotpSnafu, TowerSessionsSnafu,
+        VentError,
+    },
+    liquid_utils::compile_with_newtitle,
+    state::{db_objects::AuthorisationBackendPerson, VentState},
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::{login_required, permission_required, AuthnBackend};
+use chrono::Utc;
+use liquid::Object;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use sqlx::Connection;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tower_sessions::Session;
+
+///The session key under which we store someone who has passed the password check, but not the second factor
+const PENDING_2FA_KEY: &str = "vent.pending_2fa";
+///How long someone has to enter their code after entering their password
+const PENDING_2FA_TIMEOUT_SECS: i64 = 10 * 60;
+///How many wrong codes we accept before making someone log in again
+const MAX_2FA_ATTEMPTS: u8 = 5;
+///How many recovery codes get generated on enrolment
+const N_RECOVERY_CODES: usize = 10;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct Pending2FA {
+    person_id: i32,
+    is_enrolling: bool,
+    started_at: i64,
+    attempts: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SecondFactor {
+    NotNeeded,
+    Verify,
+    Enrol,
+}
+
+struct TotpStatus {
+    secret: Option<String>,
+    enabled: bool,
+}
+
+async fn get_totp_status(state: &VentState, person_id: i32) -> Result<TotpStatus, VentError> {
+    let rec = sqlx::query!(
+        "SELECT totp_secret, totp_enabled FROM people WHERE id = $1",
+        person_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(person_id.into()),
+    })?;
+
+    Ok(TotpStatus {
+        secret: rec.totp_secret,
+        enabled: rec.totp_enabled,
+    })
+}
+
+fn build_totp(secret: &str, username: &str, issuer: &str) -> Result<TOTP, VentError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .context(TotpSecretSnafu)?,
+        Some(issuer.replace(':', "")),
+        username.replace(':', ""),
+    )
+    .context(TotpSnafu)
+}
+
+///Checks a code against the current time step and the ones either side, to allow for clocks being a bit off - gives back which step it was for
+fn check_totp(totp: &TOTP, code: &str) -> Option<u64> {
+    let current = Utc::now().timestamp().unsigned_abs() / totp.step;
+    (current.saturating_sub(1)..=current + 1)
+        .find(|step| secrets_match(&totp.generate(step * totp.step), code))
+}
+
+///Checks a TOTP code, making sure that it is for a later time step than the last one which worked - so that each code only works once
+async fn use_totp(
+    state: &VentState,
+    person_id: i32,
+    totp: &TOTP,
+    code: &str,
+) -> Result<bool, VentError> {
+    let Some(step) = check_totp(totp, code) else {
+        return Ok(false);
+    };
+    let step = i64::try_from(step).unwrap_or(i64::MAX);
+
+    //one statement, so that two logins at once can't both use the same code
+    let accepted = sqlx::query!(
+        "UPDATE people SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2) RETURNING id",
+        person_id,
+        step
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(person_id.into()),
+    })?
+    .is_some();
+
+    if !accepted {
+        warn!(%person_id, "TOTP code was used again");
+    }
+
+    Ok(accepted)
+}
+
+///Makes a new secret and stores it against the person, without enabling 2FA yet
+async fn start_enrolment(state: &VentState, person_id: i32) -> Result<String, VentError> {
+    let raw: [u8; 20] = thread_rng().gen();
+    let Secret::Encoded(secret) = Secret::Raw(raw.to_vec()).to_encoded() else {
+        unreachable!("to_encoded always gives an encoded secret")
+    };
+
+    sqlx::query!(
+        "UPDATE people SET totp_secret = $1, totp_enabled = false, totp_last_step = NULL WHERE id = $2",
+        secret,
+        person_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(person_id.into()),
+    })?;
+
+    Ok(secret)
+}
+
+///Turns on 2FA for a person, and returns a fresh set of recovery codes to show them
+async fn finish_enrolment(state: &VentState, person_id: i32) -> Result<Vec<String>, VentError> {
+    let mut codes = vec![];
+    let mut hashed_codes = vec![];
+    for _ in 0..N_RECOVERY_CODES {
+        let code: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        hashed_codes.push(hash_password(&state.settings.auth.password_hashing, &code).await?);
+        codes.push(code);
+    }
+
+    let mut conn = state.get_connection().await?;
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    sqlx::query!(
+        "UPDATE people SET totp_enabled = true WHERE id = $1",
+        person_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(person_id.into()),
+    })?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE person_id = $1", person_id)
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+        })?;
+
+    for hashed_code in hashed_codes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (person_id, hashed_code) VALUES ($1, $2)",
+            person_id,
+            hashed_code
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+        })?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(codes)
+}
+
+async fn clear_2fa(state: &VentState, person_id: i32) -> Result<(), VentError> {
+    let mut conn = state.get_connection().await?;
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    sqlx::query!(
+        "UPDATE people SET totp_secret = NULL, totp_enabled = false, totp_last_step = NULL WHERE id = $1",
+        person_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(person_id.into()),
+    })?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE person_id = $1", person_id)
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+        })?;
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(())
+}
+
+///Checks a code against either the person's TOTP secret, or their unused recovery codes - recovery codes get used up
+async fn verify_second_factor(
+    state: &VentState,
+    person_id: i32,
+    username: &str,
+    code: &str,
+) -> Result<bool, VentError> {
+    let TotpStatus { secret, enabled } = get_totp_status(state, person_id).await?;
+    let Some(secret) = secret.filter(|_| enabled) else {
+        return Ok(false);
+    };
+
+    if use_totp(
+        state,
+        person_id,
+        &build_totp(&secret, username, &state.settings.brand.instance_name)?,
+        code,
+    )
+    .await?
+    {
+        return Ok(true);
+    }
+
+    let code = code.trim().to_ascii_lowercase();
+    let mut conn = state.get_connection().await?;
+    for rec in sqlx::query!(
+        "SELECT id, hashed_code FROM recovery_codes WHERE person_id = $1",
+        person_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingRecoveryCodes(person_id.into()),
+    })? {
+        if verify_password(&code, &rec.hashed_code).await? {
+            let deleted = sqlx::query!("DELETE FROM recovery_codes WHERE id = $1", rec.id)
+                .execute(&mut *conn)
+                .await
+                .context(SqlxSnafu {
+                    action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+                })?
+                .rows_affected();
+
+            //if it's already gone, another login used it first
+            if deleted != 1 {
+                warn!(%person_id, "Recovery code was used twice at once");
+                return Ok(false);
+            }
+
+            info!(%person_id, "Used recovery code");
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+///Works out whether someone who has just passed the password check needs a second step
+pub async fn second_factor_needed(
+    state: &VentState,
+    user: &AuthorisationBackendPerson,
+) -> Result<SecondFactor, VentError> {
+    if get_totp_status(state, user.id).await?.enabled {
+        return Ok(SecondFactor::Verify);
+    }
+
+    if state
+        .settings
+        .auth
+        .require_2fa_from
+        .is_some_and(|min| user.permissions >= min)
+    {
+        return Ok(SecondFactor::Enrol);
+    }
+
+    Ok(SecondFactor::NotNeeded)
+}
+
+///Remembers that someone has passed the password check, so that `/login_2fa` can finish logging them in
+pub async fn start_second_factor(
+    session: &Session,
+    user: &AuthorisationBackendPerson,
+    second_factor: SecondFactor,
+) -> Result<(), VentError> {
+    session
+        .insert(
+            PENDING_2FA_KEY,
+            Pending2FA {
+                person_id: user.id,
+                is_enrolling: second_factor == SecondFactor::Enrol,
+                started_at: Utc::now().timestamp(),
+                attempts: 0,
+            },
+        )
+        .await
+        .context(TowerSessionsSnafu)
+}
+
+async fn get_pending(session: &Session) -> Result<Option<Pending2FA>, VentError> {
+    let pending: Option<Pending2FA> = session
+        .get(PENDING_2FA_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(pending.filter(|p| Utc::now().timestamp() - p.started_at < PENDING_2FA_TIMEOUT_SECS))
+}
+
+///Gets the details needed to show someone their QR code for enrolment
+async fn enrolment_object(
+    state: &VentState,
+    person_id: i32,
+    username: &str,
+) -> Result<Object, VentError> {
+    let secret = match get_totp_status(state, person_id).await?.secret {
+        Some(secret) => secret,
+        None => start_enrolment(state, person_id).await?,
+    };
+    let totp = build_totp(&secret, username, &state.settings.brand.instance_name)?;
+    let qr = totp
+        .get_qr_base64()
+        .map_err(|error| TotpQrSnafu { error }.build())?;
+
+    Ok(liquid::object!({
+        "qr": qr,
+        "secret": totp.get_secret_base32(),
+    }))
+}
+
+///Gets the 2FA details to show on `/edit_user`
+pub async fn get_2fa_object(
+    state: &VentState,
+    user: &AuthorisationBackendPerson,
+) -> Result<Object, VentError> {
+    let TotpStatus { secret, enabled } = get_totp_status(state, user.id).await?;
+    let required = state
+        .settings
+        .auth
+        .require_2fa_from
+        .is_some_and(|min| user.permissions >= min);
+
+    if !enabled && secret.is_some() {
+        let enrolment = enrolment_object(state, user.id, &user.username).await?;
+        return Ok(liquid::object!({
+            "enabled": false,
+            "enrolling": true,
+            "required": required,
+            "enrolment": enrolment,
+        }));
+    }
+
+    Ok(liquid::object!({
+        "enabled": enabled,
+        "enrolling": false,
+        "required": required,
+    }))
+}
+
+#[axum::debug_handler]
+async fn get_login_2fa(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(pending) = get_pending(&session).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(user) = auth.backend.get_user(&pending.person_id).await? else {
+        return Ok(Redirect::to("/login_failure/user_not_found").into_response());
+    };
+
+    let enrolment = if pending.is_enrolling {
+        Some(enrolment_object(&state, user.id, &user.username).await?)
+    } else {
+        None
+    };
+
+    let aa = get_auth_object(auth).await?;
+    Ok(compile_with_newtitle(
+        "www/login_2fa.liquid",
+        liquid::object!({ "auth": aa, "is_enrolling": pending.is_enrolling, "enrolment": enrolment, "failed": false }),
+        &state.settings.brand.instance_name,
+        Some("Two-Factor Authentication".into()),
+    )
+    .await?
+    .into_response())
+}
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+#[axum::debug_handler]
+async fn post_login_2fa(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: GrabRemoteIP,
+    request: RequestDetails,
+    Form(CodeForm { code }): Form<CodeForm>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(mut pending) = get_pending(&session).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(user) = auth.backend.get_user(&pending.person_id).await? else {
+        return Ok(Redirect::to("/login_failure/user_not_found").into_response());
+    };
+
+    //every code counts as a login attempt, so that having the password doesn't give unlimited guesses at the second factor
+    let ip = remote_ip.as_str();
+    let attempt_id = match start_attempt(&state, &user.username, ip).await? {
+        LoginAttempt::Allowed { attempt_id } => attempt_id,
+        LoginAttempt::LockedOut => {
+            warn!(person_id = %user.id, ?ip, "2FA attempt whilst locked out");
+            return Ok(Redirect::to("/login_failure/locked_out").into_response());
+        }
+        LoginAttempt::TooSoon => {
+            warn!(person_id = %user.id, ?ip, "2FA attempt too soon after failures");
+            return Ok(Redirect::to("/login_failure/slow_down").into_response());
+        }
+    };
+
+    let worked = if pending.is_enrolling {
+        match get_totp_status(&state, user.id).await?.secret {
+            Some(secret) => {
+                use_totp(
+                    &state,
+                    user.id,
+                    &build_totp(&secret, &user.username, &state.settings.brand.instance_name)?,
+                    &code,
+                )
+                .await?
+            }
+            None => false,
+        }
+    } else {
+        verify_second_factor(&state, user.id, &user.username, &code).await?
+    };
+
+    if !worked {
+        pending.attempts += 1;
+        warn!(person_id = %user.id, attempts = %pending.attempts, "Wrong 2FA code");
+        login_failed(&state, &user.username, "wrong second factor", &request).await?;
+
+        if pending.attempts >= MAX_2FA_ATTEMPTS {
+            session
+                .remove::<Pending2FA>(PENDING_2FA_KEY)
+                .await
+                .context(TowerSessionsSnafu)?;
+            return Ok(Redirect::to("/login_failure/failed_2fa").into_response());
+        }
+
+        session
+            .insert(PENDING_2FA_KEY, pending)
+            .await
+            .context(TowerSessionsSnafu)?;
+
+        let enrolment = if pending.is_enrolling {
+            Some(enrolment_object(&state, user.id, &user.username).await?)
+        } else {
+            None
+        };
+        let aa = get_auth_object(auth).await?;
+        return Ok(compile_with_newtitle(
+            "www/login_2fa.liquid",
+            liquid::object!({ "auth": aa, "is_enrolling": pending.is_enrolling, "enrolment": enrolment, "failed": true }),
+            &state.settings.brand.instance_name,
+            Some("Two-Factor Authentication".into()),
+        )
+        .await?
+        .into_response());
+    }
+
+    record_success(&state, &user.username, attempt_id).await?;
+    session
+        .remove::<Pending2FA>(PENDING_2FA_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+    record_security_event(
+        &state,
+        SecurityEventKind::LoginSucceeded,
+        &user.username,
+        Some("second factor".into()),
+        &request,
+    )
+    .await?;
+    auth.login(&user).await?;
+
+    if pending.is_enrolling {
+        let codes = finish_enrolment(&state, user.id).await?;
+        let aa = get_auth_object(auth).await?;
+        return Ok(compile_with_newtitle(
+            "www/recovery_codes.liquid",
+            liquid::object!({ "auth": aa, "codes": codes }),
+            &state.settings.brand.instance_name,
+            Some("Recovery Codes".into()),
+        )
+        .await?
+        .into_response());
+    }
+
+    Ok(Redirect::to("/").into_response())
+}
+
+#[axum::debug_handler]
+async fn post_start_2fa(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.expect("need to be logged in to enrol in 2FA").id;
+
+    if get_totp_status(&state, current_id).await?.enabled {
+        return Ok(Redirect::to("/edit_user"));
+    }
+
+    start_enrolment(&state, current_id).await?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+#[axum::debug_handler]
+async fn post_confirm_2fa(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(CodeForm { code }): Form<CodeForm>,
+) -> Result<impl IntoResponse, VentError> {
+    let user = auth
+        .user
+        .clone()
+        .expect("need to be logged in to enrol in 2FA");
+
+    let Some(secret) = get_totp_status(&state, user.id).await?.secret else {
+        return Ok(Redirect::to("/edit_user").into_response());
+    };
+    if !use_totp(
+        &state,
+        user.id,
+        &build_totp(&secret, &user.username, &state.settings.brand.instance_name)?,
+        &code,
+    )
+    .await?
+    {
+        warn!(person_id = %user.id, "Wrong 2FA code whilst enrolling");
+        return Ok(Redirect::to("/edit_user").into_response());
+    }
+
+    let codes = finish_enrolment(&state, user.id).await?;
+
+    let aa = get_auth_object(auth).await?;
+    Ok(compile_with_newtitle(
+        "www/recovery_codes.liquid",
+        liquid::object!({ "auth": aa, "codes": codes }),
+        &state.settings.brand.instance_name,
+        Some("Recovery Codes".into()),
+    )
+    .await?
+    .into_response())
+}
+
+#[axum::debug_handler]
+async fn post_disable_2fa(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(CodeForm { code }): Form<CodeForm>,
+) -> Result<impl IntoResponse, VentError> {
+    let user = auth.user.expect("need to be logged in to disable 2FA");
+
+    if state
+        .settings
+        .auth
+        .require_2fa_from
+        .is_some_and(|min| user.permissions >= min)
+    {
+        warn!(person_id = %user.id, "Tried to disable mandatory 2FA");
+        return Ok(Redirect::to("/edit_user"));
+    }
+
+    if get_totp_status(&state, user.id).await?.enabled
+        && !verify_second_factor(&state, user.id, &user.username, &code).await?
+    {
+        warn!(person_id = %user.id, "Wrong 2FA code whilst disabling");
+        return Ok(Redirect::to("/edit_user"));
+    }
+
+    clear_2fa(&state, user.id).await?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+#[derive(Deserialize)]
+struct Reset2FA {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_reset_2fa(
+    State(state): State<VentState>,
+    Form(Reset2FA { id }): Form<Reset2FA>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Resetting 2FA");
+    clear_2fa(&state, id).await?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/reset_2fa", post(post_reset_2fa))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::EditPeople
+        ))
+        .route("/2fa/start", post(post_start_2fa))
+        .route("/2fa/confirm", post(post_confirm_2fa))
+        .route("/2fa/disable", post(post_disable_2fa))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+        .route("/login_2fa", get(get_login_2fa).post(post_login_2fa))
+}
@@ -1,5 +1,8 @@
+use crate::auth::{password::HashingAlgorithm, PermissionsRole};
+use chrono::{NaiveTime, Weekday};
 use config::{Config, ConfigError, File};
 use dotenvy::var;
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::path::PathBuf;
 use tokio::task::spawn_blocking;
@@ -16,6 +19,298 @@ pub struct NicheSettings {
     pub tech_support: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ThrottleSettings {
+    ///How far back we look when counting failed logins
+    pub window_minutes: i32,
+    ///How many failures are allowed before each further attempt has to wait a while after the last failure
+    pub delay_after: i64,
+    ///The longest anyone has to wait after a failure before trying again - any earlier attempts get turned away
+    pub max_delay_secs: u64,
+    ///How many failures for one username before the account gets locked
+    pub lockout_after: i64,
+    ///How long accounts stay locked for
+    pub lockout_minutes: i32,
+    ///How many failures from one IP before that IP can't log in to anything
+    pub ip_lockout_after: i64,
+}
+
+impl Default for ThrottleSettings {
+    fn default() -> Self {
+        Self {
+            window_minutes: 15,
+            delay_after: 3,
+            max_delay_secs: 30,
+            lockout_after: 10,
+            lockout_minutes: 30,
+            ip_lockout_after: 50,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordHashingSettings {
+    ///Which algorithm new hashes use - anything hashed differently gets upgraded on login
+    pub algorithm: HashingAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+impl Default for PasswordHashingSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: HashingAlgorithm::Argon2id,
+            argon2_memory_kib: argon2::Params::DEFAULT_M_COST,
+            argon2_iterations: argon2::Params::DEFAULT_T_COST,
+            argon2_parallelism: argon2::Params::DEFAULT_P_COST,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicySettings {
+    pub min_length: usize,
+    ///Whether to check against the bundled list of common passwords
+    pub check_common: bool,
+    ///Whether to stop people using their username or name in their password
+    pub check_personal_details: bool,
+}
+
+impl Default for PasswordPolicySettings {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            check_common: true,
+            check_personal_details: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MagicLinkSettings {
+    ///Which roles can log in with an emailed link - nobody can if this is empty
+    pub enabled_for: Vec<PermissionsRole>,
+    ///How long emailed login links work for
+    pub minutes: i32,
+}
+
+impl Default for MagicLinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled_for: vec![],
+            minutes: 10,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    ///Anyone with this role or higher must use two-factor authentication
+    pub require_2fa_from: Option<PermissionsRole>,
+    pub throttle: ThrottleSettings,
+    ///How long links to set a password work for
+    pub password_token_minutes: i32,
+    pub password_hashing: PasswordHashingSettings,
+    pub password_policy: PasswordPolicySettings,
+    ///How often expired sessions get deleted
+    pub session_cleanup_minutes: u64,
+    pub magic_link: MagicLinkSettings,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            require_2fa_from: None,
+            throttle: ThrottleSettings::default(),
+            password_token_minutes: 60,
+            password_hashing: PasswordHashingSettings::default(),
+            password_policy: PasswordPolicySettings::default(),
+            session_cleanup_minutes: 60,
+            magic_link: MagicLinkSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    #[default]
+    Turnstile,
+    HCaptcha,
+    Disabled,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CaptchaSettings {
+    #[serde(default)]
+    pub provider: CaptchaProvider,
+    pub sitekey: Option<String>,
+    pub secret: Option<String>,
+    ///Overrides the provider's `siteverify` URL - eg. to point release builds at a local stub server
+    pub verify_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OidcUsernameClaim {
+    ///The part before the `@`, as long as the domain matches `mail.username_domain`
+    #[default]
+    Email,
+    PreferredUsername,
+    Sub,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcSettings {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    ///Defaults to `{brand.domain}/oidc/callback`
+    pub redirect_url: Option<String>,
+    ///Which claim from the identity provider gets matched to `people.username`
+    #[serde(default)]
+    pub username_claim: OidcUsernameClaim,
+    ///Scopes to ask for on top of `openid` - defaults to `email` and `profile`
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    ///What the button on the login page says
+    pub button_text: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LdapSettings {
+    ///eg. `ldap://localhost:389` or `ldaps://ad.example.org`
+    pub url: String,
+    ///Used to search for people - anonymous if missing
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    ///The attribute which holds the same value as `people.username`, eg. `uid` or `sAMAccountName`
+    #[serde(default = "LdapSettings::default_username_attribute")]
+    pub username_attribute: String,
+    ///Extra filter that people have to match
+    #[serde(default = "LdapSettings::default_filter")]
+    pub filter: String,
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default = "LdapSettings::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl LdapSettings {
+    fn default_username_attribute() -> String {
+        "uid".into()
+    }
+    fn default_filter() -> String {
+        "(objectClass=person)".into()
+    }
+    fn default_timeout_secs() -> u64 {
+        5
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProxySettings {
+    ///Proxies which we believe when they say where a request came from - anything else gets the socket address
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: vec![
+                "127.0.0.0/8".parse().expect("valid loopback range"),
+                "::1/128".parse().expect("valid loopback range"),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsSettings {
+    ///PEM file with the certificate, followed by any intermediates
+    pub cert_path: PathBuf,
+    ///PEM file with the private key
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SecurityHeadersSettings {
+    pub content_security_policy: String,
+    ///How long browsers should only use HTTPS for - only sent when TLS is on, and `0` turns it off
+    pub hsts_max_age_secs: u64,
+}
+
+impl Default for SecurityHeadersSettings {
+    fn default() -> Self {
+        Self {
+            content_security_policy: [
+                "default-src 'self'",
+                //workbox comes from `storage.googleapis.com`, for the service worker in `public/sw.js`
+                "script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net https://www.googletagmanager.com https://storage.googleapis.com https://challenges.cloudflare.com https://hcaptcha.com https://*.hcaptcha.com",
+                "style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net https://hcaptcha.com https://*.hcaptcha.com",
+                "img-src 'self' data: https://http.cat https://www.googletagmanager.com",
+                "connect-src 'self' https://*.google-analytics.com https://www.googletagmanager.com https://hcaptcha.com https://*.hcaptcha.com",
+                "frame-src https://challenges.cloudflare.com https://hcaptcha.com https://*.hcaptcha.com",
+                "frame-ancestors 'none'",
+                "base-uri 'self'",
+                "form-action 'self'",
+            ]
+            .join("; "),
+            hsts_max_age_secs: 60 * 60 * 24 * 365,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReminderSettings {
+    ///How long before an event its participants and prefects get reminded - `0` turns reminders off
+    pub hours_before: i32,
+    ///How often to look for events which are coming up
+    pub check_minutes: u64,
+}
+
+impl Default for ReminderSettings {
+    fn default() -> Self {
+        Self {
+            //off unless asked for, so that upgrading doesn't suddenly start emailing everyone
+            hours_before: 0,
+            check_minutes: 15,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DigestSettings {
+    ///Whether prefects get a weekly email about past events which still have unverified participants
+    pub enabled: bool,
+    pub weekday: Weekday,
+    ///When on `weekday` to send it, in `timezone_id`
+    pub time: NaiveTime,
+}
+
+impl Default for DigestSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            weekday: Weekday::Mon,
+            time: NaiveTime::from_hms_opt(8, 0, 0).expect("valid time"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub niche: NicheSettings,
@@ -23,14 +318,98 @@ pub struct Settings {
     pub mail: MailSettings,
     pub timezone_id: String,
     pub tech_support_person: String,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub captcha: CaptchaSettings,
+    ///Single sign-on is turned off if this is missing
+    pub oidc: Option<OidcSettings>,
+    ///Only local passwords are used if this is missing
+    pub ldap: Option<LdapSettings>,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+    ///Plain HTTP is served if this is missing
+    pub tls: Option<TlsSettings>,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersSettings,
+    #[serde(default)]
+    pub reminders: ReminderSettings,
+    #[serde(default)]
+    pub prefect_digest: DigestSettings,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxSettings {
+    ///How many times to try sending an email before marking it as failed
+    pub max_attempts: i32,
+    ///How long to wait before the first retry - this doubles after each failure
+    pub base_backoff_secs: u64,
+    ///The longest to ever wait between retries
+    pub max_backoff_secs: u64,
+    ///How often to check for emails which are due a retry
+    pub poll_secs: u64,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_backoff_secs: 30,
+            max_backoff_secs: 60 * 60,
+            poll_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransportKind {
+    #[default]
+    Smtp,
+    ///Writes each email to an `.eml` file in `mail.file_path`, rather than sending it
+    File,
+    ///Keeps emails in memory so tests can look at them, and never sends them
+    Stub,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpEncryption {
+    ///TLS from the start, usually on port 465
+    #[default]
+    Tls,
+    ///Upgrades a plain connection to TLS, usually on port 587
+    StartTls,
+    ///No encryption at all - only for local test servers
+    None,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct MailSettings {
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
     pub smtp: String,
     pub username_domain: String,
+    #[serde(default)]
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub transport: MailTransportKind,
+    #[serde(default)]
+    pub encryption: SmtpEncryption,
+    ///Defaults to the usual port for `encryption`
+    pub port: Option<u16>,
+    ///Where the `file` transport puts emails
+    #[serde(default = "MailSettings::default_file_path")]
+    pub file_path: PathBuf,
+}
+
+impl MailSettings {
+    fn default_file_path() -> PathBuf {
+        "sent_emails".into()
+    }
 }
 
 impl Settings {
@@ -1,4 +1,4 @@
-use crate::{auth::{backend::VentAuthBackend, cloudflare_turnstile::CommonHeaders}, image_format::ImageFormat};
+use crate::{auth::{backend::VentAuthBackend, captcha::CommonHeaders}, image_format::ImageFormat};
 use axum::{
     http::StatusCode,
     response::{Html, IntoResponse},
@@ -29,6 +29,7 @@ pub enum LoginFailureReason {
 #[derive(Debug)]
 pub enum ChannelReason {
     SendUpdateCalMessage,
+    SendEmailWakeup,
 }
 
 #[derive(Debug)]
@@ -117,6 +118,7 @@ pub enum IOAction {
     ReadingFile(FileIdentifier),
     OpeningFile(FileIdentifier),
     CreatingFile(FileIdentifier),
+    CreatingFolder(FileIdentifier),
     DeletingFile(FileIdentifier),
     ReadingAndOpening(FileIdentifier),
     WritingToFile,
@@ -125,7 +127,7 @@ pub enum IOAction {
 
 #[derive(Debug)]
 pub enum ReqwestAction {
-    CloudflareTurntile,
+    VerifyingCaptcha,
     RErrorForStatus(Option<reqwest::StatusCode>),
     // HErrorForStatus(Option<http::StatusCode>),
     ConvertToJson(SerdeJsonAction),
@@ -140,9 +142,10 @@ pub enum ConvertingWhatToString {
 
 #[derive(Debug)]
 pub enum SerdeJsonAction {
-    CloudflareTurnstileResponse,
+    CaptchaResponse,
     ParsingLogFile,
     SessionSerde,
+    EmailOutbox,
 }
 
 #[derive(Debug)]
@@ -157,6 +160,7 @@ pub enum ThreadReason {
     LiquidCompiler,
     FindingExistingFilesWithWalkDir,
     BuildSpreadsheet,
+    HashingPassword,
 }
 
 #[derive(Debug)]
@@ -181,6 +185,13 @@ impl From<String> for DatabaseIDMethod {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum OidcAction {
+    Discovering,
+    ExchangingCode,
+    VerifyingIdToken,
+}
+
 #[derive(Debug)]
 pub enum SqlxAction {
     //TODO: possible refactor to 2 enums - action (eg. find, update), objects involved (eg. event, person)
@@ -193,6 +204,30 @@ pub enum SqlxAction {
     },
     AddingPerson,
     RemovingPerson(DatabaseIDMethod),
+    FindingPermissionOverrides(DatabaseIDMethod),
+    UpdatingPermissionOverrides(DatabaseIDMethod),
+
+    FindingRole(i32),
+    FindingAllRoles,
+    AddingRole,
+    UpdatingRole(i32),
+    RemovingRole(i32),
+
+    FindingRecoveryCodes(DatabaseIDMethod),
+    UpdatingRecoveryCodes(DatabaseIDMethod),
+
+    FindingLoginAttempts,
+    AddingLoginAttempt,
+
+    FindingPasswordToken(DatabaseIDMethod),
+    UpdatingPasswordToken(DatabaseIDMethod),
+
+    FindingLoginToken(DatabaseIDMethod),
+    UpdatingLoginToken(DatabaseIDMethod),
+
+    FindingApiTokens(DatabaseIDMethod),
+    AuthenticatingApiToken,
+    UpdatingApiTokens(DatabaseIDMethod),
 
     FindingEvent(i32),
     UpdatingEvent(i32),
@@ -237,6 +272,24 @@ pub enum SqlxAction {
     RemovingSession(Id),
     AddingSession,
     FindingSession(Id),
+    FindingSessions(DatabaseIDMethod),
+    RemovingSessions(DatabaseIDMethod),
+
+    AddingImpersonationAudit,
+
+    AddingSecurityEvent,
+    FindingSecurityEvents,
+
+    AddingEmailToOutbox,
+    FindingEmailsInOutbox,
+    UpdatingEmailInOutbox(i32),
+
+    ClaimingEventReminders,
+    ClaimingPrefectDigest,
+    FindingUnverifiedParticipants,
+
+    StartingTransaction,
+    CommittingTransaction,
 
     AcquiringConnection,
 
@@ -324,13 +377,20 @@ pub enum VentError {
     Xlsx { source: rust_xlsxwriter::XlsxError },
     #[snafu(display("Error with Encrypting: {source:?}"), context(false))]
     Bcrypt { source: bcrypt::BcryptError },
+    #[snafu(display("Error with Password Hashing: {source:?}"), context(false))]
+    PasswordHash {
+        source: argon2::password_hash::Error,
+    },
+    #[snafu(display("Error with Argon2 parameters: {source:?}"), context(false))]
+    Argon2 { source: argon2::Error },
+    #[snafu(display("Error with TOTP: {source:?}"))]
+    Totp { source: totp_rs::TotpUrlError },
+    #[snafu(display("Error parsing TOTP secret: {source:?}"))]
+    TotpSecret { source: totp_rs::SecretParseError },
+    #[snafu(display("Error making TOTP QR code: {error}"))]
+    TotpQr { error: String },
     #[snafu(display("Error converting {what:?} to string"))]
     ToStr { what: ConvertingWhatToString },
-    #[snafu(display("Error converting {header:?} to string due to {source:?}"))]
-    HeaderToStr {
-        source: http::header::ToStrError,
-        header: CommonHeaders,
-    },
     #[snafu(display("Error reqwest-ing: {source:?} whilst trying to {action:?}"))]
     Reqwest {
         source: reqwest::Error,
@@ -345,10 +405,22 @@ pub enum VentError {
         source: lettre::error::Error,
         trying_to: LettreAction,
     },
+    #[snafu(display("Error parsing email content type: {source:?}"), context(false))]
+    LettreContentType {
+        source: lettre::message::header::ContentTypeErr,
+    },
     #[snafu(display("Error with SMTP: {source:?}"), context(false))]
     LettreSMTP {
         source: lettre::transport::smtp::Error,
     },
+    #[snafu(display("Error writing email to a file: {source:?}"), context(false))]
+    LettreFile {
+        source: lettre::transport::file::Error,
+    },
+    #[snafu(display("Error with the stub email transport: {source:?}"), context(false))]
+    LettreStub {
+        source: lettre::transport::stub::Error,
+    },
     #[snafu(display("Error with CSV Files: {source:?}"), context(false))]
     Csv { source: csv_async::Error },
     #[snafu(display("JSON error: {source:?} whilst trying to {action:?}"))]
@@ -387,10 +459,26 @@ pub enum VentError {
     MalformedCSV {
         was_trying_to_get: TryingToGetFromCSV,
     },
-    #[snafu(display("Missing Cloudflare IP in headers"))]
-    MissingCFIP,
+    #[snafu(display("Missing the socket address of a request"))]
+    MissingRemoteIP,
     #[snafu(display("Failure to login due to {reason:?}"))]
     LoginFailure { reason: LoginFailureReason },
+    #[snafu(display("Invalid, expired or revoked API token"))]
+    InvalidApiToken,
+    #[snafu(display("API tokens can only be used for exports"))]
+    ApiTokenNotAllowed,
+    #[snafu(display("Missing or incorrect CSRF token - try going back and reloading the page"))]
+    CsrfMismatch,
+    #[snafu(display(
+        "Nothing can be changed whilst viewing the site as someone else - stop impersonating them first"
+    ))]
+    WhilstImpersonating,
+    #[snafu(display("Error reading the request body: {source:?}"))]
+    ReadingBody { source: axum::Error },
+    #[snafu(display("Error with LDAP: {source:?}"), context(false))]
+    Ldap { source: ldap3::LdapError },
+    #[snafu(display("Error with single sign-on trying to {action:?}: {error}"))]
+    Oidc { action: OidcAction, error: String },
 }
 
 impl From<ALError> for VentError {
@@ -445,9 +533,14 @@ impl IntoResponse for VentError {
             | VentError::Image { .. }
             | VentError::NoImageExtension { .. }
             | VentError::MalformedCSV { .. }
-            | VentError::MissingCFIP
+            | VentError::MissingRemoteIP
+            | VentError::ReadingBody { .. }
             | VentError::LoginFailure { .. } => StatusCode::BAD_REQUEST,
             VentError::PageNotFound { .. } => StatusCode::NOT_FOUND,
+            VentError::InvalidApiToken => StatusCode::UNAUTHORIZED,
+            VentError::CsrfMismatch
+            | VentError::ApiTokenNotAllowed
+            | VentError::WhilstImpersonating => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -1,4 +1,5 @@
 use crate::{
+    auth::{captcha::captcha_info, csrf::form_token},
     error::{IOAction, IOSnafu, JoinSnafu, LiquidAction, LiquidSnafu, ThreadReason, VentError},
     liquid_utils::partials::PARTIALS,
 };
@@ -12,8 +13,6 @@ use tokio::fs::read_to_string;
 
 pub mod partials;
 
-pub static CFT_SITEKEY: Lazy<String> =
-    Lazy::new(|| var("CFT_SITEKEY").expect("missing environment variable `CFT_SITEKEY`"));
 pub static DOMAIN: Lazy<(bool, String)> = Lazy::new(|| {
     if let Ok(dom) = var("DOMAIN") {
         (true, dom)
@@ -22,12 +21,8 @@ pub static DOMAIN: Lazy<(bool, String)> = Lazy::new(|| {
     }
 });
 
-pub async fn compile_with_newtitle(
-    path: impl AsRef<Path> + Debug,
-    mut globals: Object,
-    project_name: &str,
-    title_additional_info: Option<String>,
-) -> Result<Html<String>, VentError> {
+///Renders a liquid template (which can use any of the [`PARTIALS`]) with exactly the given globals
+pub async fn render(path: impl AsRef<Path> + Debug, globals: Object) -> Result<String, VentError> {
     debug!("Reading in file + partials");
 
     let path_displayed = format!("{path:?}");
@@ -36,25 +31,7 @@ pub async fn compile_with_newtitle(
     })?;
     let partial_compiler = PARTIALS.read().await.to_compiler();
 
-    debug!("Inserting globals");
-
-    let title = match title_additional_info {
-        None => project_name.to_string(),
-        Some(x) => x.to_string(),
-    };
-
-    globals.insert("cft_sitekey".into(), Value::scalar(CFT_SITEKEY.as_str()));
-    globals.insert(
-        "siteinfo".into(),
-        Value::Object(liquid::object!({
-            "instance_name": project_name,
-            "html_title": title,
-            "domain_exists": DOMAIN.0,
-            "domain": DOMAIN.1.as_str()
-        })),
-    );
-
-    let html: Result<String, VentError> = tokio::task::spawn_blocking(move || {
+    let rendered: Result<String, VentError> = tokio::task::spawn_blocking(move || {
         debug!("Compiling");
         let res = ParserBuilder::with_stdlib()
             .partials(partial_compiler)
@@ -77,7 +54,38 @@ pub async fn compile_with_newtitle(
         title: ThreadReason::LiquidCompiler,
     })?;
 
-    Ok(Html(html?))
+    rendered
+}
+
+pub async fn compile_with_newtitle(
+    path: impl AsRef<Path> + Debug,
+    mut globals: Object,
+    project_name: &str,
+    title_additional_info: Option<String>,
+) -> Result<Html<String>, VentError> {
+    debug!("Inserting globals");
+
+    let title = match title_additional_info {
+        None => project_name.to_string(),
+        Some(x) => x.to_string(),
+    };
+
+    globals.insert("captcha".into(), Value::Object(captcha_info()));
+    globals.insert(
+        "csrf_token".into(),
+        Value::scalar(form_token().await?),
+    );
+    globals.insert(
+        "siteinfo".into(),
+        Value::Object(liquid::object!({
+            "instance_name": project_name,
+            "html_title": title,
+            "domain_exists": DOMAIN.0,
+            "domain": DOMAIN.1.as_str()
+        })),
+    );
+
+    Ok(Html(render(path, globals).await?))
 }
 
 pub async fn compile(
@@ -15,38 +15,60 @@ mod liquid_utils;
 mod routes;
 mod state;
 mod image_format;
+mod security_headers;
+mod tls;
 
 pub use http;
 
 use crate::{
-    auth::{add_password, backend::VentAuthBackend, login, pg_session::PostgresStore},
+    auth::{
+        add_password,
+        api_tokens::{self, api_token_middleware},
+        backend::VentAuthBackend,
+        captcha::captcha_info_middleware,
+        csrf::csrf_middleware,
+        impersonation::{self, impersonation_middleware},
+        login, magic_link, oidc,
+        pg_session::PostgresStore,
+        security_events,
+        sessions::{self, session_metadata_middleware},
+        throttle, two_factor,
+    },
     error::not_found_fallback,
     liquid_utils::partials,
     routes::{
         add_event, add_people_to_event, add_person, calendar::get_calendar_feed, edit_person,
-        edit_self, eoy_migration, images, import_export, index::get_index, public, rewards,
-        show_people, show_events, spreadsheets::get_spreadsheet, update_events,
+        edit_self, email_outbox, eoy_migration, images, import_export, index::get_index, public,
+        rewards, roles, show_people, show_events, spreadsheets::get_spreadsheet, update_events,
     },
+    security_headers::security_headers_middleware,
     state::VentState,
+    tls::build_tls_acceptor,
 };
 use axum::{
-    extract::{DefaultBodyLimit, Request},
+    extract::{ConnectInfo, DefaultBodyLimit, Request},
+    middleware,
     response::IntoResponse,
     routing::get,
     Router,
 };
 use axum_login::{
-    tower_sessions::{Expiry, SessionManagerLayer},
+    tower_sessions::{cookie::SameSite, Expiry, SessionManagerLayer},
     AuthManagerLayerBuilder,
 };
 use http::StatusCode;
-use hyper::{body::Incoming, service::service_fn};
+use hyper::{
+    body::Incoming,
+    rt::{Read, Write},
+    service::service_fn,
+};
 use hyper_util::rt::TokioIo;
 use liquid_utils::partials::PARTIALS;
 use sqlx::postgres::PgPoolOptions;
 use std::{env::var, net::SocketAddr};
 use time::Duration;
 use tokio::{net::TcpListener, signal, sync::watch};
+use tokio_rustls::TlsAcceptor;
 use tower::{limit::ConcurrencyLimitLayer, Service};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Registry};
@@ -112,11 +134,19 @@ async fn main() {
         .await
         .expect("cannot connect to DB");
 
-    let session_layer = SessionManagerLayer::new(PostgresStore::new(pool.clone()))
-        .with_secure(false)
-        .with_expiry(Expiry::OnInactivity(Duration::days(14)));
+    let state = match VentState::new(pool.clone()).await {
+        Ok(state) => state,
+        Err(e) => {
+            error!(%e, "Unable to start up - check the settings file");
+            std::process::exit(1);
+        }
+    };
+    let tls_acceptor = state.settings.tls.as_ref().map(build_tls_acceptor);
 
-    let state = VentState::new(pool).await;
+    let session_layer = SessionManagerLayer::new(PostgresStore::new(pool))
+        .with_secure(tls_acceptor.is_some())
+        .with_same_site(SameSite::Lax) //not strict, as single sign-on needs the cookie when the identity provider redirects back
+        .with_expiry(Expiry::OnInactivity(Duration::days(14)));
 
     let auth_layer =
         AuthManagerLayerBuilder::new(VentAuthBackend::new(state.clone()), session_layer).build();
@@ -129,10 +159,20 @@ async fn main() {
         .merge(public::router())
         .merge(add_password::router())
         .merge(login::router())
+        .merge(magic_link::router())
+        .merge(oidc::router())
+        .merge(two_factor::router())
+        .merge(api_tokens::router())
+        .merge(throttle::router())
+        .merge(sessions::router())
+        .merge(impersonation::router())
+        .merge(security_events::router())
         .merge(partials::router())
         .merge(import_export::router())
         .merge(edit_self::router())
+        .merge(email_outbox::router())
         .merge(rewards::router())
+        .merge(roles::router())
         .merge(add_event::router())
         .merge(add_people_to_event::router())
         .merge(add_person::router())
@@ -145,7 +185,25 @@ async fn main() {
         .fallback(not_found_fallback)
         .layer(TraceLayer::new_for_http())
         .layer(DefaultBodyLimit::max(1024 * 1024 * 50)) //50MB i think
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_token_middleware,
+        )) //needs to be inside the auth layer
+        .layer(middleware::from_fn(csrf_middleware)) //needs to be inside the auth layer, but outside the API token layer
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            session_metadata_middleware,
+        )) //needs to be inside the auth layer, but outside the API token layer
+        .layer(middleware::from_fn(impersonation_middleware)) //needs to be inside the auth layer
         .layer(auth_layer)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            captcha_info_middleware,
+        )) //outside the auth layer, so that error pages can show the captcha too
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            security_headers_middleware,
+        )) //outside everything else, so that error pages get them too
         .layer(ConcurrencyLimitLayer::new(512)) //limit to 512 inflight reqs
         .with_state(state.clone());
 
@@ -154,17 +212,31 @@ async fn main() {
         .parse()
         .expect("need VENT_SERVER_IP to be valid");
 
-    info!(?port, "Serving: ");
+    info!(?port, tls = tls_acceptor.is_some(), "Serving: ");
 
-    serve(router, TcpListener::bind(port).await.unwrap(), state).await;
+    serve(
+        router,
+        TcpListener::bind(port).await.unwrap(),
+        tls_acceptor,
+        state,
+    )
+    .await;
 }
 
+///How long a client gets to finish the TLS handshake before we give up on it
+const TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
 //https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown/src/main.rs
-async fn serve(app: Router, listener: TcpListener, state: VentState) {
+async fn serve(
+    app: Router,
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: VentState,
+) {
     let (close_tx, close_rx) = watch::channel(());
 
     loop {
-        let (socket, _remote_addr) = tokio::select! {
+        let (socket, remote_addr) = tokio::select! {
             result = listener.accept() => {
                 result.unwrap()
             },
@@ -176,30 +248,40 @@ async fn serve(app: Router, listener: TcpListener, state: VentState) {
         let tower_service = app.clone();
         let close_rx = close_rx.clone();
         let state = state.clone();
+        let tls_acceptor = tls_acceptor.clone();
 
         tokio::spawn(async move {
-            let socket = TokioIo::new(socket);
-            let hyper_service =
-                service_fn(move |req: Request<Incoming>| tower_service.clone().call(req));
-
-            let conn = hyper::server::conn::http1::Builder::new()
-                .serve_connection(socket, hyper_service)
-                .with_upgrades();
-
-            let mut conn = std::pin::pin!(conn);
-
-            loop {
-                tokio::select! {
-                    result = conn.as_mut() => {
-                        if let Err(err) = result {
-                            error!(?err, "Failed to serve connection :(");
-                        }
-                        break;
-                    },
-                    () = shutdown_signal(state.clone()) => {
-                        conn.as_mut().graceful_shutdown();
+            match tls_acceptor {
+                Some(tls_acceptor) => {
+                    //otherwise a client which never finishes the handshake would hold on to the connection (and stop shutdown) forever
+                    let handshake = tokio::time::timeout(
+                        std::time::Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SECS),
+                        tls_acceptor.accept(socket),
+                    );
+                    let socket = tokio::select! {
+                        result = handshake => match result {
+                            Ok(Ok(socket)) => Some(socket),
+                            Ok(Err(err)) => {
+                                warn!(?err, ?remote_addr, "TLS handshake failed");
+                                None
+                            }
+                            Err(_elapsed) => {
+                                warn!(?remote_addr, "TLS handshake timed out");
+                                None
+                            }
+                        },
+                        () = shutdown_signal(state.clone()) => None,
+                    };
+
+                    if let Some(socket) = socket {
+                        serve_connection(TokioIo::new(socket), remote_addr, tower_service, state)
+                            .await;
                     }
                 }
+                None => {
+                    serve_connection(TokioIo::new(socket), remote_addr, tower_service, state)
+                        .await;
+                }
             }
 
             drop(close_rx);
@@ -211,3 +293,36 @@ async fn serve(app: Router, listener: TcpListener, state: VentState) {
 
     close_tx.closed().await;
 }
+
+async fn serve_connection(
+    socket: impl Read + Write + Unpin + Send + 'static,
+    remote_addr: SocketAddr,
+    tower_service: Router,
+    state: VentState,
+) {
+    let hyper_service = service_fn(move |mut req: Request<Incoming>| {
+        //so that the real client IP can be worked out - see `auth::client_ip`
+        req.extensions_mut().insert(ConnectInfo(remote_addr));
+        tower_service.clone().call(req)
+    });
+
+    let conn = hyper::server::conn::http1::Builder::new()
+        .serve_connection(socket, hyper_service)
+        .with_upgrades();
+
+    let mut conn = std::pin::pin!(conn);
+
+    loop {
+        tokio::select! {
+            result = conn.as_mut() => {
+                if let Err(err) = result {
+                    error!(?err, "Failed to serve connection :(");
+                }
+                break;
+            },
+            () = shutdown_signal(state.clone()) => {
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
@@ -4,12 +4,14 @@ pub mod add_person;
 pub mod calendar;
 pub mod edit_person;
 pub mod edit_self;
+pub mod email_outbox;
 pub mod eoy_migration;
 pub mod images;
 pub mod import_export;
 pub mod index;
 pub mod public;
 pub mod rewards;
+pub mod roles;
 pub mod show_people;
 pub mod show_events;
 pub mod spreadsheets;
@@ -7,7 +7,8 @@ use crate::{
 };
 use axum::{extract::State, response::IntoResponse};
 
-use icalendar::{Calendar, CalendarDateTime, Component, Event, EventLike};
+use chrono::Utc;
+use icalendar::{Calendar, CalendarDateTime, Component, Event, EventLike, EventStatus, Property};
 use snafu::ResultExt;
 use sqlx::{pool::PoolConnection, Pool, Postgres};
 use std::{collections::HashMap, time::Duration};
@@ -30,16 +31,78 @@ pub async fn get_calendar_feed(
     serve_static_file("calendar.ics").await
 }
 
+///Makes the calendar entry for one event
+///
+/// The UID is the same everywhere we send the event, so that calendar apps match up the feed and the emails
+fn calendar_event(event: &DbEvent, prefects: &str, tzid: &str, domain: &str) -> Event {
+    let DbEvent {
+        id,
+        event_name,
+        date,
+        location,
+        teacher,
+        other_info,
+        zip_file: _,
+    } = event;
+    let other_info = other_info.as_deref().unwrap_or_default();
+
+    Event::new()
+        .uid(&format!("event-{id}@{domain}"))
+        .summary(event_name)
+        .starts(CalendarDateTime::WithTimezone {
+            date_time: *date,
+            tzid: tzid.to_string(),
+        })
+        .ends(*date + chrono::Duration::minutes(45))
+        .location(location)
+        .description(&format!(
+            r"
+Teacher: {teacher}
+Other Information: {other_info}
+Prefects Attending: {prefects}"
+        ))
+        .done()
+}
+
+///Makes an iCalendar file with just one event in it, for sending to the people on that event when it changes
+///
+/// Calendar apps match it up with what they already have using the UID, and the sequence number makes sure newer versions win
+pub fn single_event_ics(
+    event: &DbEvent,
+    prefects: &str,
+    tzid: &str,
+    domain: &str,
+    cancelled: bool,
+) -> String {
+    let mut ical_event = calendar_event(event, prefects, tzid, domain);
+    ical_event
+        //only needs to go up each time we send one, and we don't keep track of how many we've sent
+        .add_property("SEQUENCE", &Utc::now().timestamp().to_string());
+    if cancelled {
+        ical_event.status(EventStatus::Cancelled);
+    }
+
+    let mut calendar = Calendar::new();
+    calendar.append_property(Property::new(
+        "METHOD",
+        if cancelled { "CANCEL" } else { "PUBLISH" },
+    ));
+    calendar.push(ical_event.done());
+    calendar.done().to_string()
+}
+
 pub fn update_calendar_thread(
     pool: Pool<Postgres>,
     mut stop_rx: BroadcastReceiver<()>,
     tzid: String,
+    domain: String,
 ) -> UnboundedSender<()> {
     let (update_tx, mut update_rx) = unbounded_channel();
 
     async fn update_events(
         mut conn: PoolConnection<Postgres>,
         tzid: String,
+        domain: String,
     ) -> Result<(), VentError> {
         let mut prefect_events: HashMap<i32, Vec<String>> = HashMap::new();
 
@@ -74,46 +137,21 @@ pub fn update_calendar_thread(
         debug!(?prefect_events, "Worked out PEs");
 
         let mut calendar = Calendar::new();
-        for DbEvent {
-            id,
-            event_name,
-            date,
-            location,
-            teacher,
-            other_info,
-            zip_file: _,
-        } in sqlx::query_as!(DbEvent, r#"SELECT * FROM events"#)
+        for event in sqlx::query_as!(DbEvent, r#"SELECT * FROM events"#)
             .fetch_all(&mut *conn)
             .await
             .context(SqlxSnafu {
                 action: SqlxAction::FindingAllEvents,
             })?
         {
-            let other_info = other_info.unwrap_or_default();
             let prefects = prefect_events
-                .get(&id)
+                .get(&event.id)
                 .map(|x| x.join(", "))
                 .unwrap_or_default();
 
-            debug!(?event_name, ?date, "Adding event to calendar");
-
-            calendar.push(
-                Event::new()
-                    .summary(&event_name)
-                    .starts(CalendarDateTime::WithTimezone {
-                        date_time: date,
-                        tzid: tzid.clone(),
-                    })
-                    .ends(date + chrono::Duration::minutes(45))
-                    .location(&location)
-                    .description(&format!(
-                        r#"
-Teacher: {teacher}
-Other Information: {other_info}
-Prefects Attending: {prefects}"#
-                    ))
-                    .done(),
-            );
+            debug!(?event.event_name, ?event.date, "Adding event to calendar");
+
+            calendar.push(calendar_event(&event, &prefects, &tzid, &domain));
         }
         calendar.name("Kingsley House Events");
 
@@ -133,7 +171,7 @@ Prefects Attending: {prefects}"#
     tokio::spawn(async move {
         match pool.acquire().await {
             Ok(conn) => {
-                if let Err(e) = update_events(conn, tzid.clone()).await {
+                if let Err(e) = update_events(conn, tzid.clone(), domain.clone()).await {
                     error!(?e, "Error updating calendar!!!");
                 }
             }
@@ -149,7 +187,7 @@ Prefects Attending: {prefects}"#
             if let Ok(()) = update_rx.try_recv() {
                 match pool.acquire().await {
                     Ok(conn) => {
-                        if let Err(e) = update_events(conn, tzid.clone()).await {
+                        if let Err(e) = update_events(conn, tzid.clone(), domain.clone()).await {
                             error!(?e, "Error updating calendar!!!");
                         }
                     }
@@ -1,11 +1,19 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsRole, PermissionsTarget,
+        get_auth_object,
+        security_events::{record_security_event, RequestDetails, SecurityEventKind},
+        sessions::get_active_sessions,
+        throttle::get_lockout_status,
+        PermissionsRole, PermissionsTarget,
     },
     error::{SqlxAction, SqlxSnafu, VentError, DatabaseIDMethod},
     liquid_utils::{compile_with_newtitle, CustomFormat},
-    routes::{rewards::Reward, FormPerson},
+    routes::{
+        rewards::Reward,
+        roles::{get_all_roles, Role},
+        FormPerson,
+    },
     state::{db_objects::DbPerson, VentState},
 };
 use axum::{
@@ -17,6 +25,9 @@ use axum::{
 use axum_login::permission_required;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use sqlx::Connection;
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
 
 #[axum::debug_handler]
 async fn get_edit_person(
@@ -139,17 +150,65 @@ ON pe.event_id = e.id AND pe.participant_id = $1 AND pe.is_verified"#,
     
     let rewards = sqlx::query_as!(Reward, "select name, first_entry_pts, second_entry_pts, id FROM rewards_received rr inner join rewards r on r.id = rr.reward_id and rr.person_id = $1", person.id).fetch_all(&mut *state.get_connection().await?).await.context(SqlxSnafu { action: SqlxAction::FindingPerson(person.id.into()) })?;
 
+    debug!("Getting permission overrides");
+
+    #[derive(Serialize)]
+    struct PermissionOverride {
+        name: &'static str,
+        snake_case_name: String,
+        role_default: bool,
+        state: &'static str,
+    }
+
+    let overrides: HashMap<_, _> = auth
+        .backend
+        .get_permission_overrides(person.id)
+        .await?
+        .into_iter()
+        .collect();
+    let role_can = auth
+        .backend
+        .get_role_permissions(person.id, person.permissions)
+        .await?;
+    let permission_overrides = PermissionsTarget::iter()
+        .map(|perm| PermissionOverride {
+            name: perm.into(),
+            snake_case_name: perm.snake_case_name(),
+            role_default: role_can.contains(&perm),
+            state: match overrides.get(&perm) {
+                None => "default",
+                Some(true) => "grant",
+                Some(false) => "deny",
+            },
+        })
+        .collect::<Vec<_>>();
+
+    let custom_role = sqlx::query!("SELECT role_id FROM people WHERE id = $1", person.id)
+        .fetch_one(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingPerson(person.id.into()),
+        })?
+        .role_id
+        .unwrap_or(-1);
+    let roles: Vec<Role> = get_all_roles(&state).await?;
+
+    let (failed_logins, locked_until) = get_lockout_status(&state, id).await?;
+    let sessions = get_active_sessions(&state, id, None).await?;
+
     debug!("Compiling");
 
     let aa = get_auth_object(auth).await?;
 
-    compile_with_newtitle("www/edit_person.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "rewards": rewards,  "auth": aa, "imgs": photos, "n_imgs": photos.len() }), &state.settings.brand.instance_name, Some(format!("Edit {} {}", person.first_name, person.surname))).await
+    compile_with_newtitle("www/edit_person.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "rewards": rewards,  "auth": aa, "imgs": photos, "n_imgs": photos.len(), "permission_overrides": permission_overrides, "roles": roles, "custom_role": custom_role, "failed_logins": failed_logins, "locked_until": locked_until, "sessions": sessions }), &state.settings.brand.instance_name, Some(format!("Edit {} {}", person.first_name, person.surname))).await
 }
 
 #[axum::debug_handler]
 async fn post_edit_person(
+    auth: Auth,
     Path(id): Path<i32>,
     State(state): State<VentState>,
+    request: RequestDetails,
     Form(FormPerson {
         first_name,
         surname,
@@ -159,10 +218,24 @@ async fn post_edit_person(
     }): Form<FormPerson>,
 ) -> Result<impl IntoResponse, VentError> {
     debug!("Editing person");
+
+    let old_permissions = sqlx::query!(
+        r#"SELECT permissions as "permissions: PermissionsRole" FROM people WHERE id = $1"#,
+        id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(id.into()),
+    })?
+    .permissions;
+
+    //a custom role only sticks around if it's still built on the same permissions
     sqlx::query!(
         r#"
 UPDATE public.people
-SET permissions=$6, first_name=$2, surname=$3, form=$4, username=$5
+SET permissions=$6, first_name=$2, surname=$3, form=$4, username=$5,
+    role_id=(SELECT r.id FROM roles r WHERE r.id = people.role_id AND r.base_role = $6)
 WHERE id=$1
         "#,
         id,
@@ -178,9 +251,116 @@ WHERE id=$1
         action: SqlxAction::UpdatingPerson(id.into()),
     })?;
 
+    if old_permissions != permissions {
+        record_security_event(
+            &state,
+            SecurityEventKind::RoleChanged,
+            &username,
+            Some(format!(
+                "{old_permissions:?} to {permissions:?} by {}",
+                actor_username(&auth)
+            )),
+            &request,
+        )
+        .await?;
+    }
+
     Ok(Redirect::to(&format!("/edit_person/{id}")))
 }
 
+#[axum::debug_handler]
+async fn post_edit_person_permissions(
+    auth: Auth,
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+    request: RequestDetails,
+    Form(overrides): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, VentError> {
+    debug!(%id, "Editing permission overrides");
+
+    let mut conn = state.get_connection().await?;
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    let role_id = overrides
+        .get("custom_role")
+        .and_then(|x| x.parse::<i32>().ok())
+        .filter(|x| *x >= 0);
+    let username = sqlx::query!(
+        r#"
+UPDATE people
+SET role_id = $2, permissions = COALESCE((SELECT base_role FROM roles WHERE id = $2), permissions)
+WHERE id = $1
+RETURNING username
+        "#,
+        id,
+        role_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(id.into()),
+    })?
+    .username;
+
+    sqlx::query!("DELETE FROM user_permissions WHERE person_id = $1", id)
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingPermissionOverrides(id.into()),
+        })?;
+
+    let mut changes = vec![];
+    for perm in PermissionsTarget::iter() {
+        let name: &'static str = perm.into();
+        let granted = match overrides.get(name).map(String::as_str) {
+            Some("grant") => true,
+            Some("deny") => false,
+            _ => continue,
+        };
+        changes.push(format!("{}{name}", if granted { "+" } else { "-" }));
+
+        sqlx::query!(
+            "INSERT INTO user_permissions (person_id, permission, granted) VALUES ($1, $2, $3)",
+            id,
+            name,
+            granted
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingPermissionOverrides(id.into()),
+        })?;
+    }
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+    drop(conn);
+
+    record_security_event(
+        &state,
+        SecurityEventKind::RoleChanged,
+        &username,
+        Some(format!(
+            "custom role {role_id:?}, overrides [{}] by {}",
+            changes.join(", "),
+            actor_username(&auth)
+        )),
+        &request,
+    )
+    .await?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+///Who is making a change, for the security log
+fn actor_username(auth: &Auth) -> String {
+    auth.user
+        .as_ref()
+        .map_or_else(|| "unknown".to_string(), |x| x.username.clone())
+}
+
 #[derive(Deserialize)]
 struct PasswordReset {
     id: i32,
@@ -190,16 +370,38 @@ struct PasswordReset {
 async fn post_reset_password(
     mut auth: Auth,
     State(state): State<VentState>,
+    request: RequestDetails,
     Form(PasswordReset { id }): Form<PasswordReset>,
 ) -> Result<impl IntoResponse, VentError> {
+    let actor = actor_username(&auth);
+
     debug!("Logging out.");
 
     if auth.user.as_ref().is_some_and(|x| x.id == id) {
         auth.logout().await?;
     }
 
+    let username = sqlx::query!(
+        "UPDATE people SET hashed_password = NULL WHERE id = $1 RETURNING username",
+        id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(id.into()),
+    })?
+    .username;
+
     debug!("Sending password reset");
     state.reset_password(id).await?;
+    record_security_event(
+        &state,
+        SecurityEventKind::PasswordResetSent,
+        &username,
+        Some(format!("by {actor}")),
+        &request,
+    )
+    .await?;
     Ok(Redirect::to("/"))
 }
 
@@ -212,6 +414,10 @@ pub fn router() -> Router<VentState> {
             PermissionsTarget::SeePeople
         ))
         .route("/reset_password", post(post_reset_password))
+        .route(
+            "/edit_person_permissions/:id",
+            post(post_edit_person_permissions),
+        )
         .route_layer(permission_required!(
             VentAuthBackend,
             login_url = "/login",
@@ -1,7 +1,12 @@
 use crate::{
     auth::{
+        api_tokens::{get_api_tokens, get_grantable_permissions},
         backend::{Auth, VentAuthBackend},
         get_auth_object,
+        password::{check_password_policy, hash_password},
+        security_events::{record_security_event, RequestDetails, SecurityEventKind},
+        sessions::get_active_sessions,
+        two_factor::get_2fa_object,
     },
     error::{SqlxAction, SqlxSnafu, VentError},
     liquid_utils::compile_with_newtitle,
@@ -9,30 +14,67 @@ use crate::{
 };
 use axum::{
     extract::State,
-    response::{IntoResponse, Redirect},
+    response::{Html, IntoResponse, Redirect},
     routing::get,
     Form, Router,
 };
 use axum_login::login_required;
-use bcrypt::{hash, DEFAULT_COST};
 use serde::Deserialize;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
-#[axum::debug_handler]
-pub async fn get_edit_user(
+pub async fn render_edit_user(
     auth: Auth,
-    State(state): State<VentState>,
-) -> Result<impl IntoResponse, VentError> {
+    session: &Session,
+    state: &VentState,
+    password_errors: Vec<String>,
+    api_token_errors: Vec<String>,
+) -> Result<Html<String>, VentError> {
+    let two_factor = get_2fa_object(
+        state,
+        auth.user
+            .as_ref()
+            .expect("need to be logged in to edit profile"),
+    )
+    .await?;
+    let api_tokens = get_api_tokens(
+        state,
+        auth.user
+            .as_ref()
+            .expect("need to be logged in to edit profile")
+            .id,
+    )
+    .await?;
+    let grantable_permissions = get_grantable_permissions(&auth).await?;
+    let sessions = get_active_sessions(
+        state,
+        auth.user
+            .as_ref()
+            .expect("need to be logged in to edit profile")
+            .id,
+        Some(session),
+    )
+    .await?;
+
     let aa = get_auth_object(auth).await?;
     compile_with_newtitle(
         "www/edit_self.liquid",
-        liquid::object!({"auth": aa}),
+        liquid::object!({"auth": aa, "two_factor": two_factor, "password_errors": password_errors, "api_token_errors": api_token_errors, "api_tokens": api_tokens, "grantable_permissions": grantable_permissions, "sessions": sessions}),
         &state.settings.brand.instance_name,
         Some("Edit Profile".into()),
     )
     .await
 }
 
+#[axum::debug_handler]
+pub async fn get_edit_user(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    render_edit_user(auth, &session, &state, vec![], vec![]).await
+}
+
 #[derive(Deserialize)]
 pub struct LoginDetails {
     pub first_name: String,
@@ -42,18 +84,44 @@ pub struct LoginDetails {
 #[axum::debug_handler]
 pub async fn post_edit_user(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
+    request: RequestDetails,
     Form(LoginDetails {
         first_name,
         surname,
         unhashed_password,
     }): Form<LoginDetails>,
 ) -> Result<impl IntoResponse, VentError> {
-    let current_id = auth.user.unwrap().id;
+    let user = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to edit profile");
+    let current_id = user.id;
+
+    let password_errors = check_password_policy(
+        &state.settings.auth.password_policy,
+        &unhashed_password,
+        &[
+            &user.username,
+            &user.first_name,
+            &user.surname,
+            &first_name,
+            &surname,
+        ],
+    );
+    if !password_errors.is_empty() {
+        debug!(%current_id, "New password didn't meet the policy");
+        return Ok(
+            render_edit_user(auth, &session, &state, password_errors, vec![])
+                .await?
+                .into_response(),
+        );
+    }
 
     debug!(%current_id, "Hashing password");
 
-    let hashed = hash(&unhashed_password, DEFAULT_COST)?;
+    let hashed = hash_password(&state.settings.auth.password_hashing, &unhashed_password).await?;
 
     debug!("Updating in DB");
 
@@ -74,7 +142,16 @@ WHERE id=$4;
         action: SqlxAction::UpdatingPerson(current_id.into()),
     })?;
 
-    Ok(Redirect::to("/"))
+    record_security_event(
+        &state,
+        SecurityEventKind::PasswordChanged,
+        &user.username,
+        Some("from profile".into()),
+        &request,
+    )
+    .await?;
+
+    Ok(Redirect::to("/").into_response())
 }
 
 pub fn router() -> Router<VentState> {
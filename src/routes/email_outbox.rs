@@ -0,0 +1,172 @@
+//! Module for a developer page showing emails which haven't been sent yet, or which have given up trying, so they can be sent again.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object,
+        tokens::{get_email_for_token, TokenPurpose},
+        PermissionsTarget,
+    },
+    error::{SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::{compile_with_newtitle, CustomFormat},
+    state::VentState,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::permission_required;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+#[axum::debug_handler]
+async fn get_email_outbox(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct OutboxEmail {
+        id: i32,
+        kind: String,
+        to_username: String,
+        status: String,
+        attempts: i32,
+        last_error: Option<String>,
+        created_at: String,
+        next_attempt_at: String,
+    }
+
+    let format = &state.settings.niche.date_time_format;
+    let emails: Vec<OutboxEmail> = sqlx::query!(
+        r#"
+SELECT id, kind, to_username, status, attempts, last_error, created_at, next_attempt_at
+FROM email_outbox
+WHERE status <> 'sent'
+ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEmailsInOutbox,
+    })?
+    .into_iter()
+    .map(|rec| OutboxEmail {
+        id: rec.id,
+        kind: rec.kind,
+        to_username: rec.to_username,
+        status: rec.status,
+        attempts: rec.attempts,
+        last_error: rec.last_error,
+        created_at: rec.created_at.to_env_string(format),
+        next_attempt_at: rec.next_attempt_at.to_env_string(format),
+    })
+    .collect();
+
+    let aa = get_auth_object(auth).await?;
+
+    compile_with_newtitle(
+        "www/email_outbox.liquid",
+        liquid::object!({ "auth": aa, "emails": emails, "max_attempts": state.settings.mail.outbox.max_attempts }),
+        &state.settings.brand.instance_name,
+        Some("Email Outbox".into()),
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct ResendEmail {
+    id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_resend_email(
+    State(state): State<VentState>,
+    Form(ResendEmail { id }): Form<ResendEmail>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Resending email from the outbox");
+
+    let mut conn = state.get_connection().await?;
+    let Some(rec) = sqlx::query!(
+        "SELECT kind, person_id FROM email_outbox WHERE id = $1 AND status <> 'sent'",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEmailsInOutbox,
+    })?
+    else {
+        return Ok(Redirect::to("/email_outbox"));
+    };
+
+    //these get rebuilt from the person, as failed ones from before links were made at send time don't have a payload
+    let new_email = match (rec.kind.as_str(), rec.person_id) {
+        ("set_password", Some(person_id)) => Some(
+            get_email_for_token(
+                state.get_connection().await?,
+                TokenPurpose::SetPassword,
+                person_id,
+                state.settings.auth.password_token_minutes,
+            )
+            .await?,
+        ),
+        ("login_link", Some(person_id)) => Some(
+            get_email_for_token(
+                state.get_connection().await?,
+                TokenPurpose::Login,
+                person_id,
+                state.settings.auth.magic_link.minutes,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+
+    match new_email {
+        Some(email) => {
+            let payload = serde_json::to_value(&email).context(SerdeJsonSnafu {
+                action: SerdeJsonAction::EmailOutbox,
+            })?;
+
+            sqlx::query!(
+                "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = now(), last_error = NULL, payload = $2 WHERE id = $1",
+                id,
+                payload
+            )
+            .execute(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingEmailInOutbox(id),
+            })?;
+        }
+        None => {
+            sqlx::query!(
+                "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = now() WHERE id = $1 AND status <> 'sent' AND payload IS NOT NULL",
+                id
+            )
+            .execute(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingEmailInOutbox(id),
+            })?;
+        }
+    }
+
+    state.wake_email_sender()?;
+
+    Ok(Redirect::to("/email_outbox"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/email_outbox", get(get_email_outbox))
+        .route("/email_outbox/resend", post(post_resend_email))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+}
@@ -166,8 +166,8 @@ pub async fn post_import_people_from_csv(
             debug!("Creating");
             sqlx::query!(
                     r#"INSERT INTO public.people
-            (first_name, surname, form, hashed_password, permissions, username, password_link_id, was_first_entry)
-            VALUES($1, $2, $3, NULL, $4, $5, NULL, $6);
+            (first_name, surname, form, hashed_password, permissions, username, was_first_entry)
+            VALUES($1, $2, $3, NULL, $4, $5, $6);
             "#,
                     first_name,
                     surname,
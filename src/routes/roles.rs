@@ -0,0 +1,274 @@
+//! Module that deals with custom roles - roles which are defined in the database, have their own set of permissions, and sit on top of a built-in [`PermissionsRole`] for ordering.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object, PermissionsRole, PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::compile_with_newtitle,
+    state::VentState,
+};
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Router,
+};
+use axum_extra::extract::Form;
+use axum_login::permission_required;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use sqlx::Connection;
+use strum::IntoEnumIterator;
+
+#[derive(Serialize)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+}
+
+///Gets all of the custom roles, for use in `select`s
+pub async fn get_all_roles(state: &VentState) -> Result<Vec<Role>, VentError> {
+    sqlx::query_as!(Role, "SELECT id, name FROM roles ORDER BY name")
+        .fetch_all(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingAllRoles,
+        })
+}
+
+#[axum::debug_handler]
+async fn get_roles(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct RolePermission {
+        name: &'static str,
+        snake_case_name: String,
+        has: bool,
+    }
+
+    #[derive(Serialize)]
+    struct FullRole {
+        id: i32,
+        name: String,
+        base_role: PermissionsRole,
+        n_people: i64,
+        permissions: Vec<RolePermission>,
+    }
+
+    debug!("Getting roles");
+
+    let roles = sqlx::query!(
+        r#"
+SELECT r.id, r.name, r.base_role as "base_role: PermissionsRole",
+    (SELECT COUNT(*) FROM people p WHERE p.role_id = r.id) as "n_people!",
+    COALESCE(array_agg(rp.permission) FILTER (WHERE rp.permission IS NOT NULL), '{}') as "permissions!"
+FROM roles r
+LEFT JOIN role_permissions rp ON rp.role_id = r.id
+GROUP BY r.id
+ORDER BY r.name
+        "#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingAllRoles,
+    })?
+    .into_iter()
+    .map(|record| {
+        let has = record
+            .permissions
+            .iter()
+            .filter_map(|x| PermissionsTarget::from_db(x))
+            .collect::<Vec<_>>();
+
+        FullRole {
+            id: record.id,
+            name: record.name,
+            base_role: record.base_role,
+            n_people: record.n_people,
+            permissions: PermissionsTarget::iter()
+                .map(|perm| RolePermission {
+                    name: perm.into(),
+                    snake_case_name: perm.snake_case_name(),
+                    has: has.contains(&perm),
+                })
+                .collect(),
+        }
+    })
+    .collect::<Vec<_>>();
+
+    let aa = get_auth_object(auth).await?;
+
+    compile_with_newtitle(
+        "www/roles.liquid",
+        liquid::object!({ "roles": roles, "auth": aa }),
+        &state.settings.brand.instance_name,
+        Some("Roles".into()),
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct NewRole {
+    name: String,
+    base_role: PermissionsRole,
+}
+
+#[axum::debug_handler]
+async fn post_add_role(
+    State(state): State<VentState>,
+    Form(NewRole { name, base_role }): Form<NewRole>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(?name, "Adding new role");
+
+    let mut conn = state.get_connection().await?;
+
+    let id = sqlx::query!(
+        "INSERT INTO roles (name, base_role) VALUES ($1, $2) RETURNING id",
+        name,
+        base_role as _
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingRole,
+    })?
+    .id;
+
+    //start off with the same permissions as the built-in role, as that's normally the closest
+    for perm in base_role.can() {
+        let perm: &'static str = perm.into();
+        sqlx::query!(
+            "INSERT INTO role_permissions (role_id, permission) VALUES ($1, $2)",
+            id,
+            perm
+        )
+        .execute(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRole(id),
+        })?;
+    }
+
+    Ok(Redirect::to("/roles"))
+}
+
+#[derive(Deserialize)]
+struct EditRole {
+    name: String,
+    base_role: PermissionsRole,
+    #[serde(default)]
+    permissions: Vec<String>,
+}
+
+#[axum::debug_handler]
+async fn post_edit_role(
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+    Form(EditRole {
+        name,
+        base_role,
+        permissions,
+    }): Form<EditRole>,
+) -> Result<impl IntoResponse, VentError> {
+    debug!(%id, "Editing role");
+
+    let mut conn = state.get_connection().await?;
+    //so that nobody ends up with half of the old role and half of the new one
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    sqlx::query!(
+        "UPDATE roles SET name = $2, base_role = $3 WHERE id = $1",
+        id,
+        name,
+        base_role as _
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingRole(id),
+    })?;
+
+    //keep everyone's built-in role in sync so that ordering checks still work
+    sqlx::query!(
+        "UPDATE people SET permissions = $2 WHERE role_id = $1",
+        id,
+        base_role as _
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingRole(id),
+    })?;
+
+    sqlx::query!("DELETE FROM role_permissions WHERE role_id = $1", id)
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRole(id),
+        })?;
+
+    for perm in permissions
+        .iter()
+        .filter_map(|x| PermissionsTarget::from_db(x))
+    {
+        let perm: &'static str = perm.into();
+        sqlx::query!(
+            "INSERT INTO role_permissions (role_id, permission) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            id,
+            perm
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRole(id),
+        })?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(Redirect::to("/roles"))
+}
+
+#[derive(Deserialize)]
+struct RemoveRole {
+    role_id: i32,
+}
+
+#[axum::debug_handler]
+async fn post_remove_role(
+    State(state): State<VentState>,
+    Form(RemoveRole { role_id }): Form<RemoveRole>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%role_id, "Removing role");
+
+    sqlx::query!("DELETE FROM roles WHERE id = $1", role_id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingRole(role_id),
+        })?;
+
+    Ok(Redirect::to("/roles"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/roles", get(get_roles))
+        .route("/add_role", post(post_add_role))
+        .route("/edit_role/:id", post(post_edit_role))
+        .route("/remove_role", post(post_remove_role))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::EditRoles
+        ))
+}
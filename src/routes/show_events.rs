@@ -5,7 +5,11 @@ use crate::{
     },
     error::{SqlxAction, SqlxSnafu, VentError},
     liquid_utils::{compile_with_newtitle, CustomFormat},
-    state::VentState,
+    state::{
+        db_objects::DbEvent,
+        event_notifications::{get_attendees, notify_event_cancelled},
+        VentState,
+    },
 };
 use axum::{
     extract::State,
@@ -131,18 +135,32 @@ async fn post_remove_event(
 ) -> Result<impl IntoResponse, VentError> {
     for event_id in event_id {
         trace!(?event_id, "Removing");
-        sqlx::query!(
+        let mut conn = state.get_connection().await?;
+        let attendees = get_attendees(&mut conn, event_id).await?;
+
+        let Some(event) = sqlx::query_as!(
+            DbEvent,
             r#"
     DELETE FROM public.events
     WHERE id=$1
+    RETURNING *
             "#,
             event_id
         )
-            .execute(&mut *state.get_connection().await?)
+            .fetch_optional(&mut *conn)
             .await
             .context(SqlxSnafu {
                 action: SqlxAction::RemovingEvent(event_id),
-            })?;
+            })?
+        else {
+            continue;
+        };
+        drop(conn);
+
+        //the event has already gone, so this shouldn't stop the rest from being removed
+        if let Err(e) = notify_event_cancelled(&state, &event, &attendees).await {
+            error!(?e, %event_id, "Error telling attendees about event cancellation");
+        }
     }
 
     Ok(Redirect::to("/show_events"))
@@ -8,6 +8,7 @@ use crate::{
     routes::FormEvent,
     state::{
         db_objects::{DbEvent, DbPerson},
+        event_notifications::notify_event_changed,
         VentState,
     },
 };
@@ -358,11 +359,28 @@ async fn post_update_event(
         how_got_in: EncodeStep::Decode,
     })?;
 
-    sqlx::query!(
+    let mut conn = state.get_connection().await?;
+
+    let old_event = sqlx::query_as!(
+        DbEvent,
+        r#"
+SELECT * FROM events WHERE id = $1
+        "#,
+        event_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEvent(event_id),
+    })?;
+
+    let new_event = sqlx::query_as!(
+        DbEvent,
         r#"
 UPDATE public.events
 SET event_name=$2, date=$3, location=$4, teacher=$5, other_info=$6
 WHERE id=$1
+RETURNING *
         "#,
         event_id,
         name,
@@ -371,13 +389,18 @@ WHERE id=$1
         teacher,
         info
     )
-    .execute(&mut *state.get_connection().await?)
+    .fetch_one(&mut *conn)
     .await
     .context(SqlxSnafu {
         action: SqlxAction::UpdatingEvent(event_id),
     })?;
+    drop(conn);
 
     state.update_events()?;
+    //the event has already been updated, so this shouldn't look like it failed
+    if let Err(e) = notify_event_changed(&state, &old_event, &new_event).await {
+        error!(?e, %event_id, "Error telling attendees about event changes");
+    }
 
     Ok(Redirect::to(&format!("/update_event/{event_id}")))
 }
@@ -411,7 +434,7 @@ RETURNING event_id
     Ok(Redirect::to(&format!("/update_event/{id}")))
 }
 #[axum::debug_handler]
-async fn get_remove_participant_from_event(
+async fn post_remove_participant_from_event(
     auth: Auth,
     State(state): State<VentState>,
     Form(Removal { relation_id }): Form<Removal>,
@@ -589,14 +612,14 @@ pub fn router() -> Router<VentState> {
         ))
         .route(
             "/remove_participant_from_event",
-            post(get_remove_participant_from_event),
+            post(post_remove_participant_from_event),
         )
         .route_layer(permission_required!(
             VentAuthBackend,
             login_url = "/login",
             PermissionsTarget::EditParticipantsOnEvents
         ))
-        .route("/remove_img/:id", get(delete_image))
+        .route("/remove_img/:id", post(delete_image))
         .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
         .route("/update_event/:id", get(get_update_event))
 }
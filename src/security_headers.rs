@@ -0,0 +1,61 @@
+//! Module for the headers which go on every response to tell browsers to be stricter about what pages can do.
+
+use crate::{cfg::Settings, state::VentState};
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{
+            CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+            X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+
+pub fn build_security_headers(settings: &Settings) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    headers.insert(
+        CONTENT_SECURITY_POLICY,
+        HeaderValue::from_str(&settings.security_headers.content_security_policy)
+            .expect("security_headers.content_security_policy must be a valid header"),
+    );
+    headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(
+        REFERRER_POLICY,
+        HeaderValue::from_static("strict-origin-when-cross-origin"),
+    );
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+
+    //only makes sense when we're the ones doing HTTPS - otherwise it's up to whatever is in front of us
+    let hsts_max_age = settings.security_headers.hsts_max_age_secs;
+    if settings.tls.is_some() && hsts_max_age > 0 {
+        headers.insert(
+            STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&format!("max-age={hsts_max_age}; includeSubDomains"))
+                .expect("max-age is always a valid header"),
+        );
+    }
+
+    headers
+}
+
+///Middleware which adds [`VentState::security_headers`] to every response, unless a route has already set its own
+pub async fn security_headers_middleware(
+    State(state): State<VentState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+
+    for (name, value) in &state.security_headers {
+        response
+            .headers_mut()
+            .entry(name)
+            .or_insert_with(|| value.clone());
+    }
+
+    response
+}
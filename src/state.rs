@@ -1,10 +1,16 @@
 pub mod db;
+pub mod event_notifications;
 pub mod mail;
+pub mod mail_transport;
+pub mod prefect_digest;
+pub mod reminders;
 
 pub mod db_objects;
 
+use axum::http::HeaderMap;
 use snafu::ResultExt;
 use sqlx::{pool::PoolConnection, Pool, Postgres};
+use std::{sync::Arc, time::Duration};
 use tokio::{
     fs::File,
     sync::{
@@ -14,46 +20,85 @@ use tokio::{
 };
 
 use crate::{
-    auth::add_password::get_email_to_be_sent_for_reset_password,
+    auth::{
+        captcha::{build_captcha, CaptchaVerifier},
+        pg_session::{delete_expired_sessions_thread, PostgresStore},
+        tokens::{get_email_for_token, TokenPurpose},
+    },
     cfg::Settings,
     error::{ChannelReason, SendSnafu, SqlxAction, SqlxSnafu, VentError},
     routes::calendar::update_calendar_thread,
+    security_headers::build_security_headers,
     state::{
         db::VentDatabase,
-        mail::{email_sender_thread, EmailToSend},
+        mail::{email_sender_thread, queue_email, EmailToSend},
+        mail_transport::MailTransport,
+        prefect_digest::prefect_digest_thread,
+        reminders::event_reminder_thread,
     },
 };
 
 #[derive(Clone, Debug)]
 pub struct VentState {
-    mail_sender: UnboundedSender<EmailToSend>,
+    mail_sender: UnboundedSender<()>,
     update_calendar_sender: UnboundedSender<()>,
     stop_senders: BroadcastSender<()>,
     pub settings: Settings,
+    pub captcha: Arc<dyn CaptchaVerifier>,
+    ///The headers to add to every response, built from the settings
+    pub security_headers: HeaderMap,
     database: VentDatabase,
 }
 
 impl VentState {
-    pub async fn new(postgres: Pool<Postgres>) -> Self {
+    pub async fn new(postgres: Pool<Postgres>) -> Result<Self, VentError> {
         let settings = Settings::new().await.expect("unable to get settings");
         let (stop_senders_tx, stop_senders_rx1) = broadcast_channel(2);
 
-        let mail_sender = email_sender_thread(settings.clone(), stop_senders_rx1);
+        let mail_sender = email_sender_thread(
+            settings.clone(),
+            postgres.clone(),
+            MailTransport::new(&settings.mail)?,
+            stop_senders_rx1,
+        );
+        event_reminder_thread(
+            settings.clone(),
+            postgres.clone(),
+            mail_sender.clone(),
+            stop_senders_tx.subscribe(),
+        );
+        prefect_digest_thread(
+            settings.clone(),
+            postgres.clone(),
+            mail_sender.clone(),
+            stop_senders_tx.subscribe(),
+        );
         let update_calendar_sender = update_calendar_thread(
             postgres.clone(),
             stop_senders_tx.subscribe(),
             settings.timezone_id.clone(),
+            settings.brand.domain.clone(),
+        );
+        delete_expired_sessions_thread(
+            PostgresStore::new(postgres.clone()),
+            stop_senders_tx.subscribe(),
+            Duration::from_secs(settings.auth.session_cleanup_minutes * 60),
         );
 
         let database = VentDatabase::new(postgres);
 
-        Self {
+        let captcha = build_captcha(&settings.captcha);
+        let security_headers = build_security_headers(&settings);
+
+        Ok(Self {
             database,
             mail_sender,
             update_calendar_sender,
             stop_senders: stop_senders_tx,
             settings,
-        }
+            captcha,
+            security_headers,
+        })
     }
 
     pub async fn get_connection(&self) -> Result<PoolConnection<Postgres>, VentError> {
@@ -62,11 +107,43 @@ impl VentState {
         })
     }
 
+    ///Adds any kind of email to the outbox, to be sent in the background
+    pub async fn send_email(&self, email: EmailToSend) -> Result<(), VentError> {
+        queue_email(&mut *self.get_connection().await?, &email).await?;
+        self.wake_email_sender()
+    }
+
+    ///Gets the email thread to look through the outbox now, rather than waiting for its next check
+    pub fn wake_email_sender(&self) -> Result<(), VentError> {
+        self.mail_sender.send(()).context(SendSnafu {
+            reason: ChannelReason::SendEmailWakeup,
+        })
+    }
+
     pub async fn reset_password(&self, user_id: i32) -> Result<(), VentError> {
-        let email =
-            get_email_to_be_sent_for_reset_password(self.get_connection().await?, user_id).await?;
+        let email = get_email_for_token(
+            self.get_connection().await?,
+            TokenPurpose::SetPassword,
+            user_id,
+            self.settings.auth.password_token_minutes,
+        )
+        .await?;
+
+        self.send_email(email).await?;
+
+        Ok(())
+    }
+
+    pub async fn send_login_link(&self, user_id: i32) -> Result<(), VentError> {
+        let email = get_email_for_token(
+            self.get_connection().await?,
+            TokenPurpose::Login,
+            user_id,
+            self.settings.auth.magic_link.minutes,
+        )
+        .await?;
 
-        self.mail_sender.send(email).expect("error sending email");
+        self.send_email(email).await?;
 
         Ok(())
     }
@@ -1,8 +1,9 @@
-use crate::auth::PermissionsRole;
+use crate::auth::{PermissionsRole, PermissionsTarget};
 use axum_login::AuthUser;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::collections::HashSet;
 
 //get everything `id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry `
 //https://github.com/launchbadge/sqlx/issues/1004
@@ -29,6 +30,9 @@ pub struct AuthorisationBackendPerson {
     pub form: String,
     hashed_password_bytes: Vec<u8>,
     pub permissions: PermissionsRole,
+    ///If this person was authenticated using an API token, the only permissions that token is allowed to use
+    #[serde(skip)]
+    pub token_scope: Option<HashSet<PermissionsTarget>>,
 }
 
 impl From<DbPerson> for AuthorisationBackendPerson {
@@ -53,6 +57,7 @@ impl From<DbPerson> for AuthorisationBackendPerson {
             form,
             hashed_password_bytes: hashed_password.unwrap_or_default().as_bytes().to_vec(),
             permissions,
+            token_scope: None,
         }
     }
 }
@@ -0,0 +1,189 @@
+//! Module that tells everyone on an event when it changes or gets cancelled, along with an updated calendar entry for it.
+
+use crate::{
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    routes::calendar::single_event_ics,
+    state::{
+        db_objects::DbEvent,
+        mail::{queue_email, EmailKind, EmailToSend, EventFieldChange},
+        VentState,
+    },
+};
+use chrono::Utc;
+use snafu::ResultExt;
+use sqlx::{Connection, PgConnection};
+
+///Someone who is on an event, either as a participant or as a prefect
+pub struct Attendee {
+    pub id: i32,
+    pub username: String,
+    pub first_name: String,
+    pub surname: String,
+    pub is_prefect: bool,
+}
+
+///Gets everyone on an event - this needs doing before the event is deleted, as that removes them too
+pub async fn get_attendees(
+    conn: &mut PgConnection,
+    event_id: i32,
+) -> Result<Vec<Attendee>, VentError> {
+    sqlx::query_as!(
+        Attendee,
+        r#"
+SELECT p.id, p.username, p.first_name, p.surname, bool_or(x.is_prefect) as "is_prefect!"
+FROM (
+    SELECT participant_id AS person_id, false AS is_prefect FROM participant_events WHERE event_id = $1
+    UNION ALL
+    SELECT prefect_id AS person_id, true AS is_prefect FROM prefect_events WHERE event_id = $1
+) x
+INNER JOIN people p ON p.id = x.person_id
+GROUP BY p.id
+        "#,
+        event_id
+    )
+    .fetch_all(conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingParticipantsOrPrefectsAtEvents {
+            event_id: Some(event_id),
+        },
+    })
+}
+
+fn prefect_names(attendees: &[Attendee]) -> String {
+    attendees
+        .iter()
+        .filter(|x| x.is_prefect)
+        .map(|x| format!("{} {}", x.first_name, x.surname))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+///Works out which details people would care about have changed
+fn changed_fields(old: &DbEvent, new: &DbEvent, format: &str) -> Vec<EventFieldChange> {
+    let mut changes = vec![];
+    let mut check = |field: &str, old: String, new: String| {
+        if old != new {
+            changes.push(EventFieldChange {
+                field: field.to_string(),
+                old,
+                new,
+            });
+        }
+    };
+
+    check("Name", old.event_name.clone(), new.event_name.clone());
+    check(
+        "Date",
+        old.date.to_env_string(format),
+        new.date.to_env_string(format),
+    );
+    check("Location", old.location.clone(), new.location.clone());
+    check("Teacher", old.teacher.clone(), new.teacher.clone());
+    check(
+        "Other Information",
+        old.other_info.clone().unwrap_or_default(),
+        new.other_info.clone().unwrap_or_default(),
+    );
+
+    changes
+}
+
+///Queues one email for each attendee (either all of them or none), then gets the mail thread to send them
+async fn queue_for_attendees(
+    state: &VentState,
+    attendees: &[Attendee],
+    kind: &EmailKind,
+) -> Result<(), VentError> {
+    if attendees.is_empty() {
+        return Ok(());
+    }
+
+    let mut conn = state.get_connection().await?;
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+    for attendee in attendees {
+        let email = EmailToSend {
+            to_id: attendee.id,
+            to_username: attendee.username.clone(),
+            to_fullname: format!("{} {}", attendee.first_name, attendee.surname),
+            kind: kind.clone(),
+        };
+        queue_email(&mut transaction, &email).await?;
+    }
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    state.wake_email_sender()
+}
+
+///Emails everyone on an event about any details which changed between `old` and `new`
+///
+/// Does nothing if the event has already happened, eg. when fixing up the details afterwards
+pub async fn notify_event_changed(
+    state: &VentState,
+    old: &DbEvent,
+    new: &DbEvent,
+) -> Result<(), VentError> {
+    if new.date < Utc::now().naive_local() {
+        return Ok(());
+    }
+
+    let changes = changed_fields(old, new, &state.settings.niche.date_time_format);
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let attendees = get_attendees(&mut *state.get_connection().await?, new.id).await?;
+    info!(event_id = %new.id, n = %attendees.len(), ?changes, "Telling attendees about event changes");
+
+    let kind = EmailKind::EventChanged {
+        event_id: new.id,
+        event_name: new.event_name.clone(),
+        changes,
+        ics: single_event_ics(
+            new,
+            &prefect_names(&attendees),
+            &state.settings.timezone_id,
+            &state.settings.brand.domain,
+            false,
+        ),
+    };
+
+    queue_for_attendees(state, &attendees, &kind).await
+}
+
+///Emails everyone who was on an event that it has been cancelled - `attendees` need getting from [`get_attendees`] before it gets deleted
+///
+/// Does nothing if the event has already happened, as that's just clearing out old events
+pub async fn notify_event_cancelled(
+    state: &VentState,
+    event: &DbEvent,
+    attendees: &[Attendee],
+) -> Result<(), VentError> {
+    if event.date < Utc::now().naive_local() {
+        return Ok(());
+    }
+
+    info!(event_id = %event.id, n = %attendees.len(), "Telling attendees about event cancellation");
+
+    let kind = EmailKind::EventCancelled {
+        event_name: event.event_name.clone(),
+        date: event
+            .date
+            .to_env_string(&state.settings.niche.date_time_format),
+        location: event.location.clone(),
+        ics: single_event_ics(
+            event,
+            &prefect_names(attendees),
+            &state.settings.timezone_id,
+            &state.settings.brand.domain,
+            true,
+        ),
+    };
+
+    queue_for_attendees(state, attendees, &kind).await
+}
@@ -1,99 +1,514 @@
 use crate::{
-    cfg::Settings,
-    error::{LettreAction, LettreEmailSnafu, VentError},
+    auth::tokens::{make_token, TokenPurpose},
+    cfg::{OutboxSettings, Settings},
+    error::{
+        LettreAction, LettreEmailSnafu, SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu,
+        VentError,
+    },
+    liquid_utils::render,
+    state::mail_transport::MailTransport,
 };
 use lettre::{
-    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
-    Tokio1Executor,
+    message::{header::ContentType, Attachment, MultiPart},
+    Message,
 };
+use liquid::{model::Value, Object};
+use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::time::Duration;
 use tokio::sync::{
     broadcast::Receiver as BroadcastReceiver,
     mpsc::{unbounded_channel, UnboundedSender},
 };
 
-#[derive(Debug)]
+///Where the email templates live - each kind of email has `{name}.subject.liquid`, `{name}.txt.liquid` and `{name}.html.liquid`
+const EMAIL_TEMPLATES_DIR: &str = "www/emails";
+
+///One detail of an event which has changed, already formatted for showing to people
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventFieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+///A past event which still has participants waiting to be verified
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnverifiedEvent {
+    pub event_id: i32,
+    pub event_name: String,
+    ///Already formatted using `niche.date_time_format`
+    pub date: String,
+    pub unverified: i64,
+}
+
+///What an email is for, along with anything that only that kind of email needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EmailKind {
+    ///The token only gets made when it's sent, so that it never sits in the outbox
+    SetPassword {
+        token_minutes: i32,
+    },
+    ///The token only gets made when it's sent, so that it never sits in the outbox
+    LoginLink {
+        token_minutes: i32,
+    },
+    EventReminder {
+        event_id: i32,
+        event_name: String,
+        ///Already formatted using `niche.date_time_format`
+        date: String,
+        location: String,
+        teacher: String,
+        other_info: String,
+        is_prefect: bool,
+    },
+    EventChanged {
+        event_id: i32,
+        event_name: String,
+        changes: Vec<EventFieldChange>,
+        ics: String,
+    },
+    EventCancelled {
+        event_name: String,
+        ///Already formatted using `niche.date_time_format`
+        date: String,
+        location: String,
+        ics: String,
+    },
+    PrefectDigest {
+        events: Vec<UnverifiedEvent>,
+    },
+}
+
+impl EmailKind {
+    ///The name of this kind's templates in [`EMAIL_TEMPLATES_DIR`]
+    pub fn template_name(&self) -> &'static str {
+        match self {
+            Self::SetPassword { .. } => "set_password",
+            Self::LoginLink { .. } => "login_link",
+            Self::EventReminder { .. } => "event_reminder",
+            Self::EventChanged { .. } => "event_changed",
+            Self::EventCancelled { .. } => "event_cancelled",
+            Self::PrefectDigest { .. } => "prefect_digest",
+        }
+    }
+
+    ///An iCalendar file to attach, along with the `METHOD` it uses
+    pub fn ics_attachment(&self) -> Option<(&str, &'static str)> {
+        match self {
+            Self::EventChanged { ics, .. } => Some((ics, "PUBLISH")),
+            Self::EventCancelled { ics, .. } => Some((ics, "CANCEL")),
+            _ => None,
+        }
+    }
+
+    ///The kind of link this email sends, and how long it lasts for
+    pub fn token_purpose(&self) -> Option<(TokenPurpose, i32)> {
+        match self {
+            Self::SetPassword { token_minutes } => {
+                Some((TokenPurpose::SetPassword, *token_minutes))
+            }
+            Self::LoginLink { token_minutes } => Some((TokenPurpose::Login, *token_minutes)),
+            _ => None,
+        }
+    }
+
+    ///The globals which only this kind of email has, available under `email` in the templates
+    ///
+    /// `token` is the link from [`Self::token_purpose`], if it has one
+    fn globals(&self, token: Option<&str>) -> Object {
+        match self {
+            Self::SetPassword { token_minutes } | Self::LoginLink { token_minutes } => {
+                liquid::object!({
+                    "token": token.unwrap_or_default(),
+                    "token_minutes": token_minutes,
+                })
+            }
+            Self::EventReminder {
+                event_id,
+                event_name,
+                date,
+                location,
+                teacher,
+                other_info,
+                is_prefect,
+            } => liquid::object!({
+                "event_id": event_id,
+                "event_name": event_name,
+                "date": date,
+                "location": location,
+                "teacher": teacher,
+                "other_info": other_info,
+                "is_prefect": is_prefect,
+            }),
+            Self::EventChanged {
+                event_id,
+                event_name,
+                changes,
+                ics: _,
+            } => liquid::object!({
+                "event_id": event_id,
+                "event_name": event_name,
+                "changes": changes,
+            }),
+            Self::EventCancelled {
+                event_name,
+                date,
+                location,
+                ics: _,
+            } => liquid::object!({
+                "event_name": event_name,
+                "date": date,
+                "location": location,
+            }),
+            Self::PrefectDigest { events } => liquid::object!({
+                "events": events,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailToSend {
-    pub to_username: String,
     pub to_id: i32,
+    pub to_username: String,
     pub to_fullname: String,
-    pub unique_id: i32,
+    pub kind: EmailKind,
+}
+
+///A fully rendered email, ready to go out
+pub struct RenderedEmail {
+    pub subject: String,
+    pub plain: String,
+    pub html: String,
+}
+
+impl EmailToSend {
+    pub async fn render(
+        &self,
+        settings: &Settings,
+        token: Option<&str>,
+    ) -> Result<RenderedEmail, VentError> {
+        let name = self.kind.template_name();
+        let globals = liquid::object!({
+            "site": {
+                "instance_name": settings.brand.instance_name.as_str(),
+                "domain": settings.brand.domain.as_str(),
+            },
+            "recipient": {
+                "id": self.to_id,
+                "username": self.to_username.as_str(),
+                "fullname": self.to_fullname.as_str(),
+            },
+            "email": Value::Object(self.kind.globals(token)),
+        });
+
+        let subject = render(
+            format!("{EMAIL_TEMPLATES_DIR}/{name}.subject.liquid"),
+            globals.clone(),
+        )
+        .await?;
+        let plain = render(
+            format!("{EMAIL_TEMPLATES_DIR}/{name}.txt.liquid"),
+            globals.clone(),
+        )
+        .await?;
+        let html = render(format!("{EMAIL_TEMPLATES_DIR}/{name}.html.liquid"), globals).await?;
+
+        Ok(RenderedEmail {
+            subject: subject.trim().to_string(),
+            plain,
+            html,
+        })
+    }
+}
+
+///Adds an email to the outbox, for [`email_sender_thread`] to send
+pub async fn queue_email(conn: &mut PgConnection, email: &EmailToSend) -> Result<(), VentError> {
+    let payload = serde_json::to_value(email).context(SerdeJsonSnafu {
+        action: SerdeJsonAction::EmailOutbox,
+    })?;
+
+    sqlx::query!(
+        "INSERT INTO email_outbox (person_id, kind, to_username, payload) VALUES ($1, $2, $3, $4)",
+        email.to_id,
+        email.kind.template_name(),
+        email.to_username,
+        payload
+    )
+    .execute(conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingEmailToOutbox,
+    })?;
+
+    Ok(())
+}
+
+///How long to wait before retrying an email which has failed `attempts` times
+fn backoff_secs(outbox: &OutboxSettings, attempts: i32) -> i64 {
+    let doublings = u32::try_from(attempts.saturating_sub(1)).unwrap_or_default();
+    let secs = outbox
+        .base_backoff_secs
+        .saturating_mul(2_u64.saturating_pow(doublings))
+        .min(outbox.max_backoff_secs);
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+///Renders an email and hands it to the mail transport - `token` is the link from [`make_token`], for kinds which need one
+async fn send_email(
+    email: EmailToSend,
+    token: Option<String>,
+    mailer: &MailTransport,
+    settings: &Settings,
+) -> Result<(), VentError> {
+    let RenderedEmail {
+        subject,
+        plain,
+        html,
+    } = email.render(settings, token.as_deref()).await?;
+
+    let project_name = &settings.brand.instance_name;
+    let from_username = &settings.mail.username;
+    let username_domain = &settings.mail.username_domain;
+    let EmailToSend {
+        to_id,
+        to_username,
+        to_fullname,
+        kind,
+    } = email;
+
+    let body = MultiPart::alternative_plain_html(plain, html);
+    let body = match kind.ics_attachment() {
+        Some((ics, method)) => {
+            let content_type =
+                ContentType::parse(&format!("text/calendar; charset=utf-8; method={method}"))?;
+            MultiPart::mixed().multipart(body).singlepart(
+                Attachment::new("event.ics".to_string()).body(ics.to_string(), content_type),
+            )
+        }
+        None => body,
+    };
+
+    let m = Message::builder()
+        .from(format!("{project_name} NoReply <{from_username}>").parse()?)
+        .to(format!("{to_fullname} <{to_username}@{username_domain}>").parse()?)
+        .subject(subject)
+        .multipart(body)
+        .context(LettreEmailSnafu {
+            trying_to: LettreAction::BuildMessage,
+        })?;
+
+    info!(%to_fullname, %to_id, kind = kind.template_name(), "Sending email.");
+
+    mailer.send(m).await?;
+
+    Ok(())
 }
 
+///Sends out everything in the outbox which is due to be sent
 pub fn email_sender_thread(
     settings: Settings,
+    pool: Pool<Postgres>,
+    mailer: MailTransport,
     mut stop_rx: BroadcastReceiver<()>,
-) -> UnboundedSender<EmailToSend> {
-    let mail_settings = settings.mail.clone();
-    let (msg_tx, mut msg_rx) = unbounded_channel();
-
-    async fn send_email(
-        EmailToSend {
-            to_username,
-            to_id,
-            to_fullname,
-            unique_id,
-        }: EmailToSend,
-        mailer: &AsyncSmtpTransport<Tokio1Executor>,
-        from_username: &str,
-        username_domain: &str,
-        project_name: &str,
-        project_domain: &str,
-    ) -> Result<(), VentError> {
-        let m = Message::builder()
-            .from(format!("{project_name} NoReply <{from_username}>").parse()?)
-            .to(format!("{to_fullname} <{to_username}@{username_domain}>").parse()?)
-            .subject(format!("{project_name} - Add Password"))
-            .body(format!(
-                r#"Dear {to_fullname},
+) -> UnboundedSender<()> {
+    let (wakeup_tx, mut wakeup_rx) = unbounded_channel();
 
-You've just tried to login to {project_name}, but you don't have a password set yet.
+    ///The most emails to take out of the outbox at once
+    const BATCH_SIZE: i64 = 20;
+    ///How long an email stays claimed whilst being sent - if we stop halfway through, it gets tried again after this
+    const CLAIM_SECS: i64 = 5 * 60;
 
-To set one, go to {project_domain}/add_password/{to_id}?code={unique_id}.
+    async fn send_due_emails(
+        pool: &Pool<Postgres>,
+        mailer: &MailTransport,
+        settings: &Settings,
+    ) -> Result<(), VentError> {
+        let mut conn = pool.acquire().await.context(SqlxSnafu {
+            action: SqlxAction::AcquiringConnection,
+        })?;
 
-Have a nice day!"#
-            ))
-            .context(LettreEmailSnafu {
-                trying_to: LettreAction::BuildMessage,
-            })?;
+        let due = sqlx::query!(
+            r#"
+UPDATE email_outbox
+SET attempts = attempts + 1, next_attempt_at = now() + make_interval(secs => $1::BIGINT)
+WHERE id IN (
+    SELECT id FROM email_outbox
+    WHERE status = 'pending' AND next_attempt_at <= now()
+    ORDER BY next_attempt_at
+    LIMIT $2
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING id, payload, attempts
+            "#,
+            CLAIM_SECS,
+            BATCH_SIZE
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingEmailsInOutbox,
+        })?;
 
-        info!(%to_fullname, %to_id, numbers=%unique_id, "Sending email.");
+        for rec in due {
+            let result = match rec.payload.map(serde_json::from_value::<EmailToSend>) {
+                Some(Ok(email)) => {
+                    //a new link for every attempt, which also stops the links from any earlier attempts working
+                    let token = match email.kind.token_purpose() {
+                        Some((purpose, minutes)) => {
+                            make_token(&mut conn, purpose, email.to_id, minutes)
+                                .await
+                                .map(Some)
+                        }
+                        None => Ok(None),
+                    };
 
-        mailer.send(m).await?;
+                    match token {
+                        Ok(token) => send_email(email, token, mailer, settings)
+                            .await
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    }
+                }
+                Some(Err(e)) => Err(format!("Unable to read email from the outbox: {e}")),
+                None => Err("Email was missing from the outbox".to_string()),
+            };
+
+            match result {
+                Ok(()) => {
+                    //nothing needs the payload once it has been sent
+                    sqlx::query!(
+                        "UPDATE email_outbox SET status = 'sent', sent_at = now(), payload = NULL, last_error = NULL WHERE id = $1",
+                        rec.id
+                    )
+                    .execute(&mut *conn)
+                    .await
+                    .context(SqlxSnafu {
+                        action: SqlxAction::UpdatingEmailInOutbox(rec.id),
+                    })?;
+                }
+                Err(e) if rec.attempts >= settings.mail.outbox.max_attempts => {
+                    error!(id = %rec.id, attempts = %rec.attempts, ?e, "Giving up on sending email");
+                    sqlx::query!(
+                        "UPDATE email_outbox SET status = 'failed', last_error = $2 WHERE id = $1",
+                        rec.id,
+                        e
+                    )
+                    .execute(&mut *conn)
+                    .await
+                    .context(SqlxSnafu {
+                        action: SqlxAction::UpdatingEmailInOutbox(rec.id),
+                    })?;
+                }
+                Err(e) => {
+                    let wait = backoff_secs(&settings.mail.outbox, rec.attempts);
+                    warn!(id = %rec.id, attempts = %rec.attempts, %wait, ?e, "Error sending email, will retry");
+                    sqlx::query!(
+                        "UPDATE email_outbox SET last_error = $2, next_attempt_at = now() + make_interval(secs => $3::BIGINT) WHERE id = $1",
+                        rec.id,
+                        e,
+                        wait
+                    )
+                    .execute(&mut *conn)
+                    .await
+                    .context(SqlxSnafu {
+                        action: SqlxAction::UpdatingEmailInOutbox(rec.id),
+                    })?;
+                }
+            }
+        }
 
         Ok(())
     }
 
     tokio::spawn(async move {
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&mail_settings.smtp)
-            .expect("unable to get relay")
-            .credentials(Credentials::new(
-                mail_settings.username.clone(),
-                mail_settings.password.clone(),
-            ))
-            .build();
+        let poll_interval = Duration::from_secs(settings.mail.outbox.poll_secs);
 
         loop {
-            if let Some(ret) = tokio::select! {
+            //also goes through anything left over from before a restart
+            if let Err(e) = send_due_emails(&pool, &mailer, &settings).await {
+                error!(?e, "Error sending emails from the outbox");
+            }
+
+            tokio::select! {
                 _stop = stop_rx.recv() => {
                     info!("Mail thread stopping");
-                    Some(Ok::<_, VentError>(()))
+                    return;
                 },
-                msg = msg_rx.recv() => match msg {
-                    None => Some(Ok(())),
-                    Some(msg) => {
-                        if let Err(e) = send_email(msg, &mailer, &mail_settings.username, &mail_settings.username_domain, &settings.brand.instance_name, &settings.brand.domain).await {
-                            error!(?e, "Error sending email");
-                        }
-
-                        None
-                    }
-                }
-            } {
-                return ret;
+                wakeup = wakeup_rx.recv() => if wakeup.is_none() {
+                    return;
+                },
+                () = tokio::time::sleep(poll_interval) => {},
             }
         }
     });
 
-    msg_tx
+    wakeup_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquid_utils::partials::PARTIALS;
+    use config::{Config, File, FileFormat};
+
+    const SETTINGS: &str = r#"
+timezone_id = "Europe/London"
+tech_support_person = "Tech Support"
+
+[niche]
+date_time_format = "%c"
+tech_support = "https://example.org"
+
+[brand]
+instance_name = "Test House"
+domain = "events.example.org"
+
+[mail]
+username = "noreply@example.org"
+username_domain = "example.org"
+transport = "stub"
+"#;
+
+    #[tokio::test]
+    async fn stub_transport_keeps_sent_emails() {
+        //the email templates include partials, which normally get loaded in `main`
+        PARTIALS.write().await.reload().await;
+
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(SETTINGS, FileFormat::Toml))
+            .build()
+            .and_then(Config::try_deserialize)
+            .expect("test settings should be valid");
+        let mailer = MailTransport::new(&settings.mail).expect("stub transport should always work");
+
+        send_email(
+            EmailToSend {
+                to_id: 1,
+                to_username: "someone".to_string(),
+                to_fullname: "Some One".to_string(),
+                kind: EmailKind::LoginLink { token_minutes: 15 },
+            },
+            Some("abc123".to_string()),
+            &mailer,
+            &settings,
+        )
+        .await
+        .expect("sending through the stub transport should work");
+
+        let sent = mailer
+            .stub_messages()
+            .await
+            .expect("should be using the stub transport");
+        assert_eq!(sent.len(), 1);
+
+        let (envelope, message) = &sent[0];
+        assert_eq!(envelope.to().len(), 1);
+        assert_eq!(envelope.to()[0].to_string(), "someone@example.org");
+        assert!(message.contains("Subject: Test House - Login Link"));
+        assert!(message.contains("abc123"));
+    }
 }
@@ -0,0 +1,100 @@
+//! Module that picks how emails actually leave the server, as set in `mail.transport` - SMTP, `.eml` files on disk, or an in-memory stub for tests.
+
+use crate::{
+    cfg::{MailSettings, MailTransportKind, SmtpEncryption},
+    error::{IOAction, IOSnafu, VentError},
+};
+#[cfg(test)]
+use lettre::address::Envelope;
+use lettre::{
+    transport::{smtp::authentication::Credentials, stub::AsyncStubTransport},
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use snafu::ResultExt;
+use std::fmt::{Debug, Formatter};
+
+pub enum MailTransport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Stub(AsyncStubTransport),
+}
+
+impl Debug for MailTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Smtp(_) => "MailTransport::Smtp",
+            Self::File(_) => "MailTransport::File",
+            Self::Stub(_) => "MailTransport::Stub",
+        })
+    }
+}
+
+impl MailTransport {
+    pub fn new(settings: &MailSettings) -> Result<Self, VentError> {
+        Ok(match settings.transport {
+            MailTransportKind::Smtp => {
+                let builder = match settings.encryption {
+                    SmtpEncryption::Tls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.smtp)?
+                    }
+                    SmtpEncryption::StartTls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.smtp)?
+                    }
+                    SmtpEncryption::None => {
+                        warn!("Sending emails over SMTP without any encryption");
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.smtp)
+                    }
+                };
+                let builder = match settings.port {
+                    Some(port) => builder.port(port),
+                    None => builder,
+                };
+
+                Self::Smtp(
+                    builder
+                        .credentials(Credentials::new(
+                            settings.username.clone(),
+                            settings.password.clone(),
+                        ))
+                        .build(),
+                )
+            }
+            MailTransportKind::File => {
+                std::fs::create_dir_all(&settings.file_path).context(IOSnafu {
+                    action: IOAction::CreatingFolder(settings.file_path.clone().into()),
+                })?;
+                info!(path = ?settings.file_path, "Writing emails to files rather than sending them");
+                Self::File(AsyncFileTransport::new(&settings.file_path))
+            }
+            MailTransportKind::Stub => {
+                warn!("Using the stub mail transport - no emails will actually be sent");
+                Self::Stub(AsyncStubTransport::new_ok())
+            }
+        })
+    }
+
+    pub async fn send(&self, message: Message) -> Result<(), VentError> {
+        match self {
+            Self::Smtp(transport) => {
+                transport.send(message).await?;
+            }
+            Self::File(transport) => {
+                transport.send(message).await?;
+            }
+            Self::Stub(transport) => {
+                transport.send(message).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Everything that has been given to the stub transport, for tests to check - `None` when using any other transport
+    #[cfg(test)]
+    pub async fn stub_messages(&self) -> Option<Vec<(Envelope, String)>> {
+        match self {
+            Self::Stub(transport) => Some(transport.messages().await),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Module that emails each prefect once a week about past events of theirs which still have unverified participants, as set in `prefect_digest`.
+
+use crate::{
+    cfg::{DigestSettings, Settings},
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::mail::{queue_email, EmailKind, EmailToSend, UnverifiedEvent},
+};
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use snafu::ResultExt;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tokio::sync::{broadcast::Receiver as BroadcastReceiver, mpsc::UnboundedSender};
+
+///How often to check whether a digest is due
+const CHECK_SECS: u64 = 5 * 60;
+///If we weren't running when a digest was due, it still goes out if we start within this long - otherwise it waits for next week
+const CATCH_UP_HOURS: i64 = 24;
+
+///Works out the most recent time a digest should have gone out, in local time
+fn latest_scheduled(digest: &DigestSettings, now: NaiveDateTime) -> NaiveDateTime {
+    let days_back =
+        (7 + now.weekday().num_days_from_monday() - digest.weekday.num_days_from_monday()) % 7;
+    let scheduled = (now.date() - ChronoDuration::days(days_back.into())).and_time(digest.time);
+
+    if scheduled > now {
+        scheduled - ChronoDuration::weeks(1)
+    } else {
+        scheduled
+    }
+}
+
+///Sends out the digest for `scheduled_for`, unless it has already gone out
+///
+/// Returns how many prefects were emailed
+async fn queue_digest(
+    pool: &Pool<Postgres>,
+    settings: &Settings,
+    scheduled_for: NaiveDateTime,
+) -> Result<usize, VentError> {
+    let mut transaction = pool.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    //claiming the run in the same transaction as adding the emails means that it only ever goes out once, even if we stop halfway through
+    let claimed = sqlx::query!(
+        "INSERT INTO prefect_digest_runs (scheduled_for) VALUES ($1) ON CONFLICT DO NOTHING RETURNING scheduled_for",
+        scheduled_for
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::ClaimingPrefectDigest,
+    })?;
+    if claimed.is_none() {
+        return Ok(0);
+    }
+
+    let rows = sqlx::query!(
+        r#"
+SELECT p.id as prefect_id, p.username, p.first_name, p.surname, e.id as event_id, e.event_name, e.date, COUNT(DISTINCT pe.relation_id) as "unverified!"
+FROM prefect_events pr
+INNER JOIN people p ON p.id = pr.prefect_id
+INNER JOIN events e ON e.id = pr.event_id
+INNER JOIN participant_events pe ON pe.event_id = e.id AND pe.is_verified = false
+WHERE e.date < now()
+GROUP BY p.id, e.id
+ORDER BY p.id, e.date
+        "#
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingUnverifiedParticipants,
+    })?;
+
+    let format = &settings.niche.date_time_format;
+    let mut emails: Vec<EmailToSend> = vec![];
+    for rec in rows {
+        let event = UnverifiedEvent {
+            event_id: rec.event_id,
+            event_name: rec.event_name,
+            date: rec.date.to_env_string(format),
+            unverified: rec.unverified,
+        };
+
+        //the rows are ordered by prefect, so each prefect's events are next to each other
+        match emails.last_mut() {
+            Some(EmailToSend {
+                to_id,
+                kind: EmailKind::PrefectDigest { events },
+                ..
+            }) if *to_id == rec.prefect_id => events.push(event),
+            _ => emails.push(EmailToSend {
+                to_id: rec.prefect_id,
+                to_username: rec.username,
+                to_fullname: format!("{} {}", rec.first_name, rec.surname),
+                kind: EmailKind::PrefectDigest {
+                    events: vec![event],
+                },
+            }),
+        }
+    }
+
+    for email in &emails {
+        queue_email(&mut transaction, email).await?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(emails.len())
+}
+
+///Regularly checks whether this week's digest is due, and sends it if so
+///
+/// Does nothing if `prefect_digest.enabled` is off
+pub fn prefect_digest_thread(
+    settings: Settings,
+    pool: Pool<Postgres>,
+    mail_sender: UnboundedSender<()>,
+    mut stop_rx: BroadcastReceiver<()>,
+) {
+    if !settings.prefect_digest.enabled {
+        info!("Prefect digests are turned off");
+        return;
+    }
+
+    let tz: Tz = settings.timezone_id.parse().unwrap_or_else(|e| {
+        error!(?e, timezone_id = ?settings.timezone_id, "Unable to parse timezone for prefect digests - using UTC");
+        chrono_tz::UTC
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now().with_timezone(&tz).naive_local();
+            let scheduled_for = latest_scheduled(&settings.prefect_digest, now);
+
+            if now - scheduled_for < ChronoDuration::hours(CATCH_UP_HOURS) {
+                match queue_digest(&pool, &settings, scheduled_for).await {
+                    Ok(0) => debug!(?scheduled_for, "No prefect digests to send"),
+                    Ok(n) => {
+                        info!(%n, ?scheduled_for, "Queued prefect digests");
+                        if let Err(e) = mail_sender.send(()) {
+                            error!(?e, "Error waking up mail thread for prefect digests");
+                        }
+                    }
+                    Err(e) => error!(?e, "Error queueing prefect digests"),
+                }
+            }
+
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Prefect digest thread stopping");
+                    return;
+                },
+                () = tokio::time::sleep(Duration::from_secs(CHECK_SECS)) => {},
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+
+    fn digest(weekday: Weekday) -> DigestSettings {
+        DigestSettings {
+            enabled: true,
+            weekday,
+            time: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+        }
+    }
+
+    ///A Monday
+    fn monday() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, 24).unwrap()
+    }
+
+    #[test]
+    fn same_day_before_time_is_last_week() {
+        let now = monday().and_hms_opt(7, 59, 0).unwrap();
+        assert_eq!(
+            latest_scheduled(&digest(Weekday::Mon), now),
+            NaiveDate::from_ymd_opt(2024, 6, 17)
+                .unwrap()
+                .and_hms_opt(8, 0, 0)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn same_day_after_time_is_today() {
+        let now = monday().and_hms_opt(8, 1, 0).unwrap();
+        assert_eq!(
+            latest_scheduled(&digest(Weekday::Mon), now),
+            monday().and_hms_opt(8, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn exactly_on_time_is_today() {
+        let now = monday().and_hms_opt(8, 0, 0).unwrap();
+        assert_eq!(latest_scheduled(&digest(Weekday::Mon), now), now);
+    }
+
+    #[test]
+    fn every_weekday_offset() {
+        for days_after_monday in 0..7 {
+            let now = (monday() + ChronoDuration::days(days_after_monday))
+                .and_hms_opt(12, 0, 0)
+                .unwrap();
+
+            for weekday in [
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ] {
+                let scheduled = latest_scheduled(&digest(weekday), now);
+
+                assert_eq!(scheduled.weekday(), weekday, "now = {now}");
+                assert_eq!(scheduled.time(), NaiveTime::from_hms_opt(8, 0, 0).unwrap());
+                assert!(scheduled <= now, "{scheduled} is after {now}");
+                assert!(
+                    now - scheduled < ChronoDuration::weeks(1),
+                    "{scheduled} is more than a week before {now}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Module that emails everyone on an event a while before it starts, as set in `reminders`.
+
+use crate::{
+    cfg::Settings,
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::mail::{queue_email, EmailKind, EmailToSend},
+};
+use snafu::ResultExt;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tokio::sync::{broadcast::Receiver as BroadcastReceiver, mpsc::UnboundedSender};
+
+///Queues reminders for everyone on an event that starts within `reminders.hours_before`, and hasn't already been reminded about it
+///
+/// Returns how many reminders were queued
+async fn queue_due_reminders(
+    pool: &Pool<Postgres>,
+    settings: &Settings,
+) -> Result<usize, VentError> {
+    let mut transaction = pool.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+
+    //marking them as sent in the same transaction as adding them to the outbox means that nobody gets a reminder twice, even if we stop halfway through
+    let due = sqlx::query!(
+        r#"
+WITH due AS (
+    SELECT DISTINCT ON (x.event_id, x.person_id) x.event_id, x.person_id, x.is_prefect
+    FROM (
+        SELECT event_id, participant_id AS person_id, false AS is_prefect FROM participant_events
+        UNION ALL
+        SELECT event_id, prefect_id AS person_id, true AS is_prefect FROM prefect_events
+    ) x
+    INNER JOIN events e ON e.id = x.event_id
+    WHERE e.date > now() AND e.date <= now() + make_interval(hours => $1)
+    ORDER BY x.event_id, x.person_id, x.is_prefect DESC
+), claimed AS (
+    INSERT INTO event_reminders_sent (event_id, person_id)
+    SELECT event_id, person_id FROM due
+    ON CONFLICT DO NOTHING
+    RETURNING event_id, person_id
+)
+SELECT c.event_id as "event_id!", c.person_id as "person_id!", d.is_prefect as "is_prefect!", e.event_name, e.date, e.location, e.teacher, e.other_info, p.username, p.first_name, p.surname
+FROM claimed c
+INNER JOIN due d ON d.event_id = c.event_id AND d.person_id = c.person_id
+INNER JOIN events e ON e.id = c.event_id
+INNER JOIN people p ON p.id = c.person_id
+        "#,
+        settings.reminders.hours_before
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::ClaimingEventReminders,
+    })?;
+
+    let format = &settings.niche.date_time_format;
+    for rec in &due {
+        let email = EmailToSend {
+            to_id: rec.person_id,
+            to_username: rec.username.clone(),
+            to_fullname: format!("{} {}", rec.first_name, rec.surname),
+            kind: EmailKind::EventReminder {
+                event_id: rec.event_id,
+                event_name: rec.event_name.clone(),
+                date: rec.date.to_env_string(format),
+                location: rec.location.clone(),
+                teacher: rec.teacher.clone(),
+                other_info: rec.other_info.clone().unwrap_or_default(),
+                is_prefect: rec.is_prefect,
+            },
+        };
+        queue_email(&mut transaction, &email).await?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(due.len())
+}
+
+///Regularly looks for events which are coming up, and queues reminders for everyone on them
+///
+/// Does nothing if `reminders.hours_before` is `0`
+pub fn event_reminder_thread(
+    settings: Settings,
+    pool: Pool<Postgres>,
+    mail_sender: UnboundedSender<()>,
+    mut stop_rx: BroadcastReceiver<()>,
+) {
+    if settings.reminders.hours_before <= 0 {
+        info!("Event reminders are turned off");
+        return;
+    }
+
+    let period = Duration::from_secs(settings.reminders.check_minutes * 60);
+
+    tokio::spawn(async move {
+        loop {
+            match queue_due_reminders(&pool, &settings).await {
+                Ok(0) => debug!("No event reminders due"),
+                Ok(n) => {
+                    info!(%n, "Queued event reminders");
+                    if let Err(e) = mail_sender.send(()) {
+                        error!(?e, "Error waking up mail thread for event reminders");
+                    }
+                }
+                Err(e) => error!(?e, "Error queueing event reminders"),
+            }
+
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Event reminders thread stopping");
+                    return;
+                },
+                () = tokio::time::sleep(period) => {},
+            }
+        }
+    });
+}
@@ -0,0 +1,30 @@
+//! Module for serving over HTTPS, if `tls` is set in the settings.
+
+use crate::cfg::TlsSettings;
+use std::{fs::File, io::BufReader, sync::Arc};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+///Reads in the certificate and key - only done once at startup, so this panics if anything is wrong
+pub fn build_tls_acceptor(settings: &TlsSettings) -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(&settings.cert_path).expect("unable to open TLS certificate"),
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .expect("unable to read TLS certificate");
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(&settings.key_path).expect("unable to open TLS key"),
+    ))
+    .expect("unable to read TLS key")
+    .expect("no private key found in TLS key file");
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("invalid TLS certificate or key");
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    info!(cert = ?settings.cert_path, "Loaded TLS certificate");
+
+    TlsAcceptor::from(Arc::new(config))
+}